@@ -0,0 +1,68 @@
+@tool
+extends EditorDebuggerPlugin
+
+## Time in milliseconds after which a server or client that stopped reporting is removed.
+const SOURCE_TIMEOUT := 3000
+
+const COLUMNS := ["Peer", "Type", "Address", "RTT", "Loss", "Sent", "Received"]
+const CONNECTION_TYPES := ["None", "Direct", "Relay", "Mixed"]
+
+## The tree of each debugger session.
+var trees := {}
+
+func _has_capture(capture: String) -> bool:
+	return capture == "godot_iroh"
+
+func _capture(message: String, data: Array, session_id: int) -> bool:
+	if message != "godot_iroh:peers":
+		return false
+	if trees.has(session_id):
+		_update_source(trees[session_id], data[0], data[1])
+	return true
+
+func _setup_session(session_id: int) -> void:
+	var tree := Tree.new()
+	tree.name = "Iroh"
+	tree.hide_root = true
+	tree.columns = COLUMNS.size()
+	tree.column_titles_visible = true
+	for column in COLUMNS.size():
+		tree.set_column_title(column, COLUMNS[column])
+		tree.set_column_expand(column, column == 0 or column == 2)
+		tree.set_column_custom_minimum_width(column, 80)
+	tree.create_item()
+	trees[session_id] = tree
+
+	var session := get_session(session_id)
+	session.started.connect(func(): _clear(tree))
+	session.add_session_tab(tree)
+
+func _update_source(tree: Tree, source: String, peers: Dictionary) -> void:
+	var now := Time.get_ticks_msec()
+	var source_item: TreeItem = null
+	for item in tree.get_root().get_children():
+		if item.get_text(0) == source:
+			source_item = item
+		elif now - int(item.get_metadata(0)) > SOURCE_TIMEOUT:
+			item.free()
+	if source_item == null:
+		source_item = tree.create_item()
+		source_item.set_text(0, source)
+	source_item.set_metadata(0, now)
+
+	for item in source_item.get_children():
+		item.free()
+	for peer_id in peers:
+		var stats: Dictionary = peers[peer_id]
+		var item := tree.create_item(source_item)
+		item.set_text(0, str(peer_id))
+		item.set_text(1, CONNECTION_TYPES[stats["connection_type"]])
+		item.set_text(2, stats["remote_address"])
+		item.set_text(3, "%.1f ms" % stats["rtt"])
+		item.set_text(4, "%.1f %%" % (stats["packet_loss"] * 100.0))
+		item.set_text(5, String.humanize_size(stats["bytes_sent"]))
+		item.set_text(6, String.humanize_size(stats["bytes_received"]))
+
+func _clear(tree: Tree) -> void:
+	for item in tree.get_root().get_children():
+		item.free()
@@ -0,0 +1,11 @@
+@tool
+extends EditorPlugin
+
+var debugger_plugin: EditorDebuggerPlugin
+
+func _enter_tree() -> void:
+	debugger_plugin = preload("debugger_plugin.gd").new()
+	add_debugger_plugin(debugger_plugin)
+
+func _exit_tree() -> void:
+	remove_debugger_plugin(debugger_plugin)
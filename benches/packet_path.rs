@@ -0,0 +1,202 @@
+//! Measures the cost of moving packets between the game and the network.
+//!
+//! Each benchmark compares the current path, which shares and reuses buffers, with
+//! the previous one, which copied the packet at each step. Run with:
+//!
+//! ```sh
+//! cargo bench --bench packet_path
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use godot_iroh::bench::{IrohCompressionMode, OutgoingPacket, PacketReader, write_packet};
+use iroh::endpoint::{RecvStream, SendStream};
+use iroh::{Endpoint, RelayMode, Watcher};
+use tokio::io::AsyncReadExt;
+
+const ALPN: &[u8] = b"godot-iroh/bench";
+
+/// The number of peers a packet is broadcast to.
+const PEERS: usize = 32;
+
+/// The sizes of the packets sent in each benchmark.
+const PACKET_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+fn main() -> anyhow::Result<()> {
+    println!("Broadcast to {PEERS} peers");
+    for size in PACKET_SIZES {
+        bench_broadcast(size);
+    }
+
+    println!();
+    println!("Reliable stream over loopback");
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        for size in PACKET_SIZES {
+            bench_reliable_stream(size).await?;
+        }
+        Ok(())
+    })
+}
+
+/// Prepares a packet for each peer of a broadcast.
+fn bench_broadcast(size: usize) {
+    let packet = vec![7u8; size];
+    let iterations = (64 * 1024 * 1024 / (size * PEERS)).max(100);
+
+    let copying = measure(iterations, || {
+        for _ in 0..PEERS {
+            black_box(Bytes::from(packet.to_vec()));
+        }
+    });
+    let shared = measure(iterations, || {
+        let mut outgoing = OutgoingPacket::new(&packet);
+        for _ in 0..PEERS {
+            black_box(outgoing.encode(IrohCompressionMode::None));
+        }
+    });
+    report(size, iterations, copying, shared);
+}
+
+/// Sends packets on a reliable stream between two endpoints of the same process.
+async fn bench_reliable_stream(size: usize) -> anyhow::Result<()> {
+    let count = (256 * 1024 * 1024 / size).clamp(1000, 100_000);
+    let packet = Bytes::from(vec![7u8; size]);
+
+    let sent_packet = packet.clone();
+    let copying = transfer(
+        move |mut send| async move {
+            for _ in 0..count {
+                write_packet_copying(&mut send, &sent_packet).await?;
+            }
+            Ok(send)
+        },
+        move |mut recv| async move {
+            for _ in 0..count {
+                black_box(read_packet_copying(&mut recv).await?);
+            }
+            Ok(())
+        },
+    )
+    .await?;
+
+    let sent_packet = packet.clone();
+    let zero_copy = transfer(
+        move |mut send| async move {
+            for _ in 0..count {
+                write_packet(&mut send, sent_packet.clone()).await?;
+            }
+            Ok(send)
+        },
+        move |mut recv| async move {
+            let mut reader = PacketReader::default();
+            for _ in 0..count {
+                black_box(reader.read_packet(&mut recv, usize::MAX).await?);
+            }
+            Ok(())
+        },
+    )
+    .await?;
+    report(size, count, copying, zero_copy);
+    Ok(())
+}
+
+/// Opens a stream between two new endpoints and returns the time taken by the
+/// receiver to read everything written by the sender.
+async fn transfer<S, SF, R, RF>(sender: S, receiver: R) -> anyhow::Result<Duration>
+where
+    S: FnOnce(SendStream) -> SF,
+    SF: Future<Output = anyhow::Result<SendStream>> + Send + 'static,
+    R: FnOnce(RecvStream) -> RF,
+    RF: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let server = Endpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await?;
+    let client = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await?;
+    let server_addr = server.node_addr().initialized().await;
+
+    let accept = tokio::spawn({
+        let server = server.clone();
+        async move {
+            let incoming = server.accept().await.expect("the server endpoint closed");
+            anyhow::Ok(incoming.await?)
+        }
+    });
+    let connection = client.connect(server_addr, ALPN).await?;
+    let remote_connection = accept.await??;
+
+    // The stream is only announced to the receiver once data is written on it
+    let mut send = connection.open_uni().await?;
+    send.write_all(&[0]).await?;
+    let mut recv = remote_connection.accept_uni().await?;
+    recv.read_u8().await?;
+
+    let start = Instant::now();
+    let sending = tokio::spawn(sender(send));
+    tokio::spawn(receiver(recv)).await??;
+    let elapsed = start.elapsed();
+    sending.await??.finish()?;
+
+    client.close().await;
+    server.close().await;
+    Ok(elapsed)
+}
+
+/// Writes a packet the way it was done before packets were shared with the stream.
+async fn write_packet_copying(stream: &mut SendStream, packet: &[u8]) -> anyhow::Result<()> {
+    let mut prefix = Vec::new();
+    let mut len = packet.len() as u64;
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            prefix.push(byte);
+            break;
+        }
+        prefix.push(byte | 0x80);
+    }
+    stream.write_all(&prefix).await?;
+    stream.write_all(packet).await?;
+    Ok(())
+}
+
+/// Reads a packet the way it was done before packets were taken from the received chunks.
+async fn read_packet_copying(stream: &mut RecvStream) -> anyhow::Result<Vec<u8>> {
+    let mut len = 0u64;
+    for index in 0.. {
+        let byte = stream.read_u8().await?;
+        len |= ((byte & 0x7f) as u64) << (7 * index);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut packet = vec![0u8; len as usize];
+    AsyncReadExt::read_exact(stream, &mut packet).await?;
+    Ok(packet)
+}
+
+fn measure(iterations: usize, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed()
+}
+
+fn report(size: usize, iterations: usize, before: Duration, after: Duration) {
+    let per_iteration = |duration: Duration| duration.as_secs_f64() * 1e9 / iterations as f64;
+    println!(
+        "  {size:>6} B: {:>10.0} ns -> {:>10.0} ns per iteration ({:.2}x)",
+        per_iteration(before),
+        per_iteration(after),
+        before.as_secs_f64() / after.as_secs_f64(),
+    );
+}
@@ -31,9 +31,25 @@ func _on_join_room_pressed() -> void:
 
 func _on_create_room_pressed() -> void:
 	var server := IrohServer.start()
+	server.started.connect(_on_server_started)
+	server.start_failed.connect(_on_server_start_failed)
 	multiplayer.multiplayer_peer = server
+	$JoinBox/ConnectionString.editable = false
+	$JoinBox/JoinRoom.disabled = true
+	$CreateRoom.disabled = true
+
+func _on_server_started() -> void:
 	visible = false
 	server_started.emit()
 
+func _on_server_start_failed() -> void:
+	$ErrorLabel.text = multiplayer.multiplayer_peer.start_error()
+	$JoinBox/ConnectionString.editable = true
+	$JoinBox/JoinRoom.disabled = false
+	$CreateRoom.disabled = false
+
 func _on_server_stopped() -> void:
 	visible = true
+	$JoinBox/ConnectionString.editable = true
+	$JoinBox/JoinRoom.disabled = false
+	$CreateRoom.disabled = false
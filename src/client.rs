@@ -1,24 +1,40 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::mem::replace;
+use std::time::Duration;
 
 use bytes::Bytes;
 use godot::classes::multiplayer_peer::{ConnectionStatus, TransferMode};
 use godot::classes::{IMultiplayerPeerExtension, MultiplayerPeerExtension};
 use godot::global::Error;
 use godot::prelude::*;
-use iroh::Endpoint;
+use iroh::{Endpoint, SecretKey};
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::task::JoinHandle;
 
-use crate::connection::IrohConnection;
-use crate::{ALPN, IrohRuntime};
+use crate::IrohRuntime;
+use crate::compression::OutgoingPacket;
+use crate::config::{ConnectionConfig, IrohCompressionMode, IrohConfig};
+use crate::connection::{self, IrohConnection};
+use crate::connection_type::{IrohConnectionType, connection_type_code, remote_address};
+use crate::debugger::DebuggerReporter;
+use crate::disconnect::DisconnectReason;
+use crate::monitors;
+use crate::secret_key::IrohSecretKey;
+
+/// The maximum time between two reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 enum ClientStatus {
     Connecting(JoinHandle<anyhow::Result<(Endpoint, i32, IrohConnection)>>),
     Connected {
         endpoint: Endpoint,
         peer_id: i32,
-        connection: IrohConnection,
+        connection: Box<IrohConnection>,
+    },
+    Reconnecting {
+        endpoint: Endpoint,
+        peer_id: i32,
+        handle: JoinHandle<anyhow::Result<IrohConnection>>,
     },
     Failed(anyhow::Error),
     Disconnected,
@@ -29,13 +45,39 @@ enum ClientStatus {
 struct IrohClient {
     base: Base<MultiplayerPeerExtension>,
     status: ClientStatus,
+    connection_string: String,
+    config: ConnectionConfig,
+    disconnect_reason: Option<DisconnectReason>,
     received_packets: VecDeque<(i32, TransferMode, Bytes)>,
+    debugger_reporter: DebuggerReporter,
+    channel_priorities: HashMap<i32, i32>,
+    channel_compression: HashMap<i32, IrohCompressionMode>,
     transfer_channel: i32,
     transfer_mode: TransferMode,
 }
 
 #[godot_api]
 impl IrohClient {
+    /// Emitted when the connection to the server has been lost and the client
+    /// tries to reconnect, if the `reconnect_attempts` of the configuration is not 0.
+    ///
+    /// The client keeps its peer id and its connection status stays `CONNECTED` while
+    /// reconnecting, but the packets sent in the meantime are lost. If every attempt
+    /// fails, the `multiplayer.server_disconnected` signal is emitted.
+    #[signal]
+    fn reconnecting();
+
+    /// Emitted when the client has reconnected to the server with the same peer id.
+    #[signal]
+    fn reconnected();
+
+    /// Emitted when the paths used to reach the server are known or have changed,
+    /// for example when a direct connection replaces a relayed one.
+    ///
+    /// The peer id is always 1, and the connection types are listed in `IrohConnectionType`.
+    #[signal]
+    fn connection_type_changed(peer_id: i32, connection_type: i32);
+
     /// Connect to an existing server using the connection string.
     ///
     /// If there is an error connecting to the server, the
@@ -44,23 +86,31 @@ impl IrohClient {
     /// [Self::connection_error] function.
     #[func]
     fn connect(node_id: GString) -> Gd<Self> {
-        let node_id = node_id.to_string();
-        let handle = IrohRuntime::spawn(async {
-            let endpoint = Endpoint::builder()
-                .alpns(vec![ALPN.to_vec()])
-                .discovery_n0()
-                .bind()
-                .await?;
-            let (peer_id, connection) = IrohConnection::connect(endpoint.clone(), node_id).await?;
-            Ok((endpoint, peer_id, connection))
-        });
-        Gd::from_init_fn(|base| Self {
-            base,
-            status: ClientStatus::Connecting(handle),
-            received_packets: VecDeque::new(),
-            transfer_channel: 0,
-            transfer_mode: TransferMode::RELIABLE,
-        })
+        Self::start_connecting(node_id.to_string(), &IrohConfig::new_gd().bind(), None)
+    }
+
+    /// Connect to an existing server using the connection string and the given configuration.
+    ///
+    /// This behaves like [Self::connect] but the endpoint of the client
+    /// is created using the given configuration. If the configuration is invalid,
+    /// as reported by `IrohConfig.validate`, the connection fails right away.
+    #[func]
+    fn connect_with_config(node_id: GString, config: Gd<IrohConfig>) -> Gd<Self> {
+        Self::start_connecting(node_id.to_string(), &config.bind(), None)
+    }
+
+    /// Connect to an existing server using the connection string and the given secret key.
+    ///
+    /// This behaves like [Self::connect] but the client will be identified
+    /// on the network by the node id of the given secret key.
+    #[func]
+    fn connect_with_secret_key(node_id: GString, secret_key: Gd<IrohSecretKey>) -> Gd<Self> {
+        let secret_key = secret_key.bind().key.clone();
+        Self::start_connecting(
+            node_id.to_string(),
+            &IrohConfig::new_gd().bind(),
+            Some(secret_key),
+        )
     }
 
     /// Returns the error message that occurred when connecting to the server.
@@ -74,6 +124,245 @@ impl IrohClient {
         }
         GString::new()
     }
+
+    /// Closes the connection to the server, sending it the given code and reason.
+    ///
+    /// Codes are listed in `IrohDisconnectReason`.
+    #[func]
+    fn close_with_reason(&mut self, code: i32, reason: GString) {
+        let Ok(code) = u32::try_from(code) else {
+            godot_error!("invalid disconnect code {code}");
+            return;
+        };
+        self.close_with(DisconnectReason::new(code, reason.to_string()), true);
+    }
+
+    /// Returns the paths used to reach the server, as listed in `IrohConnectionType`.
+    #[func]
+    fn connection_type(&self) -> i32 {
+        match &self.status {
+            ClientStatus::Connected { connection, .. } => {
+                connection_type_code(connection.connection_type())
+            }
+            _ => IrohConnectionType::NONE,
+        }
+    }
+
+    /// Returns the address used to reach the server: its UDP address
+    /// for direct connections, or the url of its relay server.
+    #[func]
+    fn remote_address(&self) -> GString {
+        match &self.status {
+            ClientStatus::Connected { connection, .. } => {
+                GString::from(remote_address(connection.connection_type()))
+            }
+            _ => GString::new(),
+        }
+    }
+
+    /// Returns the statistics of the connection to the server.
+    ///
+    /// The keys of the dictionary are listed in the README. Returns an empty
+    /// dictionary if the client is not connected.
+    #[func]
+    fn get_stats(&self) -> Dictionary {
+        match &self.status {
+            ClientStatus::Connected { connection, .. } => connection.stats(),
+            _ => Dictionary::new(),
+        }
+    }
+
+    /// Sets the priority of a reliable channel, 0 by default.
+    ///
+    /// When the connection is congested, packets of channels with a higher priority
+    /// are sent first, so that gameplay packets are not delayed by large transfers.
+    #[func]
+    fn set_channel_priority(&mut self, channel: i32, priority: i32) {
+        self.channel_priorities.insert(channel, priority);
+        if let ClientStatus::Connected { connection, .. } = &mut self.status {
+            connection.set_channel_priority(channel, priority);
+        }
+    }
+
+    /// Returns the priority of a reliable channel.
+    #[func]
+    fn channel_priority(&self, channel: i32) -> i32 {
+        self.channel_priorities
+            .get(&channel)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the compression of the packets sent on a channel,
+    /// overriding the `compression` of the configuration.
+    ///
+    /// Packets are sent uncompressed if the server doesn't support the compression mode.
+    #[func]
+    fn set_channel_compression(&mut self, channel: i32, compression: IrohCompressionMode) {
+        self.channel_compression.insert(channel, compression);
+        if let ClientStatus::Connected { connection, .. } = &mut self.status {
+            connection.set_channel_compression(channel, compression);
+        }
+    }
+
+    /// Returns the compression of the packets sent on a channel.
+    #[func]
+    fn channel_compression(&self, channel: i32) -> IrohCompressionMode {
+        self.channel_compression
+            .get(&channel)
+            .copied()
+            .unwrap_or(self.config.compression)
+    }
+
+    /// Returns the number of bytes waiting to be sent to the server.
+    #[func]
+    fn queued_bytes(&self) -> i64 {
+        match &self.status {
+            ClientStatus::Connected { connection, .. } => connection.queued_bytes() as i64,
+            _ => 0,
+        }
+    }
+
+    /// Returns the code describing why the client has been disconnected from the server,
+    /// or why the connection failed.
+    ///
+    /// Codes are listed in `IrohDisconnectReason`.
+    /// Returns -1 if the client has not been disconnected.
+    #[func]
+    fn disconnect_code(&self) -> i32 {
+        self.disconnect_reason
+            .as_ref()
+            .map(|reason| reason.code as i32)
+            .unwrap_or(-1)
+    }
+
+    /// Returns the message describing why the client has been disconnected from the server,
+    /// or why the connection failed.
+    #[func]
+    fn disconnect_reason(&self) -> GString {
+        self.disconnect_reason
+            .as_ref()
+            .map(|reason| GString::from(reason.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+impl IrohClient {
+    fn apply_channel_settings(&self, connection: &mut IrohConnection) {
+        for (channel, priority) in &self.channel_priorities {
+            connection.set_channel_priority(*channel, *priority);
+        }
+        for (channel, compression) in &self.channel_compression {
+            connection.set_channel_compression(*channel, *compression);
+        }
+    }
+
+    fn close_with(&mut self, reason: DisconnectReason, force: bool) {
+        let endpoint = match &self.status {
+            ClientStatus::Connected {
+                endpoint,
+                connection,
+                ..
+            } => {
+                connection.close_with_reason(&reason);
+                endpoint.clone()
+            }
+            ClientStatus::Reconnecting {
+                endpoint, handle, ..
+            } => {
+                handle.abort();
+                endpoint.clone()
+            }
+            _ => return,
+        };
+        IrohRuntime::spawn(async move { endpoint.close().await });
+        self.status = ClientStatus::Disconnected;
+        self.disconnect_reason = Some(reason);
+        if !force {
+            self.base_mut()
+                .emit_signal("peer_disconnected", &[1i32.to_variant()]);
+        }
+    }
+
+    fn start_connecting(
+        node_id: String,
+        config: &IrohConfig,
+        secret_key: Option<SecretKey>,
+    ) -> Gd<Self> {
+        let connection_string = node_id.clone();
+        let config = config.endpoint_config();
+        let connection_config = config
+            .as_ref()
+            .map(|config| config.connection.clone())
+            .unwrap_or_default();
+        // An invalid configuration fails the connection when the client is first polled
+        let handle = IrohRuntime::spawn(async move {
+            let mut config = config?;
+            if let Some(secret_key) = secret_key {
+                config.builder = config.builder.secret_key(secret_key);
+            }
+            let endpoint = config.builder.bind().await?;
+            let (peer_id, connection) =
+                IrohConnection::connect(endpoint.clone(), &config.connection, node_id, None)
+                    .await?;
+            Ok((endpoint, peer_id, connection))
+        });
+        Gd::from_init_fn(|base| Self {
+            base,
+            status: ClientStatus::Connecting(handle),
+            connection_string,
+            config: connection_config,
+            disconnect_reason: None,
+            received_packets: VecDeque::new(),
+            debugger_reporter: DebuggerReporter::default(),
+            channel_priorities: HashMap::new(),
+            channel_compression: HashMap::new(),
+            transfer_channel: 0,
+            transfer_mode: TransferMode::RELIABLE,
+        })
+    }
+}
+
+/// Tries to reconnect to the server with the same peer id, waiting longer after each failure.
+async fn reconnect(
+    endpoint: Endpoint,
+    config: ConnectionConfig,
+    connection_string: String,
+    peer_id: i32,
+) -> anyhow::Result<IrohConnection> {
+    let mut delay = config.reconnect_delay;
+    let mut attempts = config.reconnect_attempts;
+    loop {
+        tokio::time::sleep(delay).await;
+        let error = match IrohConnection::connect(
+            endpoint.clone(),
+            &config,
+            connection_string.clone(),
+            Some(peer_id),
+        )
+        .await
+        {
+            Ok((new_peer_id, connection)) if new_peer_id == peer_id => return Ok(connection),
+            Ok(_) => {
+                return Err(DisconnectReason::new(
+                    DisconnectReason::CLOSED,
+                    "the server did not restore the peer id of the client",
+                )
+                .into());
+            }
+            Err(error) => error,
+        };
+
+        // Only retry if the server could not be reached
+        attempts -= 1;
+        let connection_lost = error
+            .downcast_ref::<DisconnectReason>()
+            .is_none_or(DisconnectReason::is_connection_lost);
+        if attempts == 0 || !connection_lost {
+            return Err(error);
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
 }
 
 #[godot_api]
@@ -81,19 +370,26 @@ impl IMultiplayerPeerExtension for IrohClient {
     fn poll(&mut self) {
         let mut notify_connection = false;
         let mut notify_disconnection = false;
+        let mut notify_reconnecting = false;
+        let mut notify_reconnected = false;
+        let mut notify_connection_type = None;
         self.status = match replace(&mut self.status, ClientStatus::Disconnected) {
             ClientStatus::Connecting(handle) => {
                 if handle.is_finished() {
                     match IrohRuntime::block_on(handle) {
-                        Ok(Ok((endpoint, peer_id, connection))) => {
+                        Ok(Ok((endpoint, peer_id, mut connection))) => {
                             notify_connection = true;
+                            self.apply_channel_settings(&mut connection);
                             ClientStatus::Connected {
                                 endpoint,
                                 peer_id,
-                                connection,
+                                connection: Box::new(connection),
                             }
                         }
-                        Ok(Err(error)) => ClientStatus::Failed(error),
+                        Ok(Err(error)) => {
+                            self.disconnect_reason = error.downcast_ref().cloned();
+                            ClientStatus::Failed(error)
+                        }
                         Err(error) => ClientStatus::Failed(error.into()),
                     }
                 } else {
@@ -104,38 +400,124 @@ impl IMultiplayerPeerExtension for IrohClient {
                 endpoint,
                 peer_id,
                 mut connection,
-            } => loop {
-                match connection.receive_packet() {
-                    Ok(packet) => self.received_packets.push_back(packet),
-                    Err(TryRecvError::Disconnected) => {
-                        notify_disconnection = true;
-                        break ClientStatus::Disconnected;
+            } => {
+                if connection.update_connection_type() {
+                    notify_connection_type =
+                        Some(connection_type_code(connection.connection_type()));
+                }
+                loop {
+                    match connection.receive_packet() {
+                        Ok(packet) => {
+                            monitors::record_queued(1);
+                            self.received_packets.push_back(packet);
+                        }
+                        Err(TryRecvError::Disconnected) => {
+                            let reason = connection.disconnect_reason();
+                            let should_reconnect =
+                                self.config.reconnect_attempts > 0 && reason.is_connection_lost();
+                            self.disconnect_reason = Some(reason);
+                            if should_reconnect {
+                                notify_reconnecting = true;
+                                let handle = IrohRuntime::spawn(reconnect(
+                                    endpoint.clone(),
+                                    self.config.clone(),
+                                    self.connection_string.clone(),
+                                    peer_id,
+                                ));
+                                break ClientStatus::Reconnecting {
+                                    endpoint,
+                                    peer_id,
+                                    handle,
+                                };
+                            }
+                            notify_disconnection = true;
+                            break ClientStatus::Disconnected;
+                        }
+                        Err(TryRecvError::Empty) => {
+                            break ClientStatus::Connected {
+                                endpoint,
+                                peer_id,
+                                connection,
+                            };
+                        }
+                    }
+                }
+            }
+            ClientStatus::Reconnecting {
+                endpoint,
+                peer_id,
+                handle,
+            } => {
+                if handle.is_finished() {
+                    match IrohRuntime::block_on(handle) {
+                        Ok(Ok(mut connection)) => {
+                            notify_reconnected = true;
+                            self.apply_channel_settings(&mut connection);
+                            self.disconnect_reason = None;
+                            ClientStatus::Connected {
+                                endpoint,
+                                peer_id,
+                                connection: Box::new(connection),
+                            }
+                        }
+                        Ok(Err(error)) => {
+                            notify_disconnection = true;
+                            if let Some(reason) = error.downcast_ref::<DisconnectReason>() {
+                                self.disconnect_reason = Some(reason.clone());
+                            }
+                            ClientStatus::Disconnected
+                        }
+                        Err(_) => {
+                            notify_disconnection = true;
+                            ClientStatus::Disconnected
+                        }
                     }
-                    Err(TryRecvError::Empty) => {
-                        break ClientStatus::Connected {
-                            endpoint,
-                            peer_id,
-                            connection,
-                        };
+                } else {
+                    ClientStatus::Reconnecting {
+                        endpoint,
+                        peer_id,
+                        handle,
                     }
                 }
-            },
+            }
             status => status,
         };
+        if notify_reconnecting {
+            self.base_mut().emit_signal("reconnecting", &[]);
+        }
+        if notify_reconnected {
+            self.base_mut().emit_signal("reconnected", &[]);
+        }
         if notify_connection {
             self.base_mut()
                 .emit_signal("peer_connected", &[1i32.to_variant()]);
         }
+        if let Some(connection_type) = notify_connection_type {
+            self.base_mut().emit_signal(
+                "connection_type_changed",
+                &[1i32.to_variant(), connection_type.to_variant()],
+            );
+        }
         if notify_disconnection {
             self.base_mut()
                 .emit_signal("peer_disconnected", &[1i32.to_variant()]);
         }
+
+        // Send the statistics of the connection to the editor
+        let instance_id = self.base().instance_id();
+        let peers = match &self.status {
+            ClientStatus::Connected { connection, .. } => Some((1, connection.as_ref())),
+            _ => None,
+        };
+        self.debugger_reporter
+            .report(|| format!("IrohClient {instance_id}"), peers);
     }
 
     fn get_connection_status(&self) -> ConnectionStatus {
         match self.status {
             ClientStatus::Connecting(_) => ConnectionStatus::CONNECTING,
             ClientStatus::Connected { .. } => ConnectionStatus::CONNECTED,
+            ClientStatus::Reconnecting { .. } => ConnectionStatus::CONNECTED,
             ClientStatus::Failed(_) => ConnectionStatus::DISCONNECTED,
             ClientStatus::Disconnected => ConnectionStatus::DISCONNECTED,
         }
@@ -149,29 +531,28 @@ impl IMultiplayerPeerExtension for IrohClient {
         if peer_id != 1 {
             return;
         }
-        if let ClientStatus::Connected { endpoint, .. } = &self.status {
-            let endpoint_clone = endpoint.clone();
-            IrohRuntime::spawn(async move { endpoint_clone.close().await });
-            self.status = ClientStatus::Disconnected;
-            if !force {
-                self.base_mut()
-                    .emit_signal("peer_disconnected", &[1i32.to_variant()]);
-            }
-        }
+        self.close_with(DisconnectReason::from_code(DisconnectReason::CLOSED), force);
     }
 
     fn get_unique_id(&self) -> i32 {
         match &self.status {
             ClientStatus::Connected { peer_id, .. } => *peer_id,
+            ClientStatus::Reconnecting { peer_id, .. } => *peer_id,
             _ => -1,
         }
     }
 
     fn get_max_packet_size(&self) -> i32 {
         if self.transfer_mode == TransferMode::RELIABLE {
-            u16::MAX as i32
+            self.config.max_packet_size.try_into().unwrap_or(i32::MAX)
         } else {
-            1024
+            let max_packet_size = match &self.status {
+                ClientStatus::Connected { connection, .. } => {
+                    connection.max_unreliable_packet_size()
+                }
+                _ => connection::max_unreliable_packet_size(None),
+            };
+            max_packet_size as i32
         }
     }
 
@@ -199,7 +580,10 @@ impl IMultiplayerPeerExtension for IrohClient {
 
     fn get_packet_script(&mut self) -> PackedByteArray {
         match self.received_packets.pop_front() {
-            Some((_, _, packet)) => packet.to_vec().into(),
+            Some((_, _, packet)) => {
+                monitors::record_queued(-1);
+                PackedByteArray::from(&packet[..])
+            }
             _ => PackedByteArray::new(),
         }
     }
@@ -223,10 +607,13 @@ impl IMultiplayerPeerExtension for IrohClient {
     fn set_target_peer(&mut self, _peer_id: i32) {}
 
     fn put_packet_script(&mut self, buffer: PackedByteArray) -> Error {
-        if let ClientStatus::Connected { connection, .. } = &mut self.status {
-            connection.send_packet(self.transfer_channel, self.transfer_mode, buffer.to_vec());
+        match &mut self.status {
+            ClientStatus::Connected { connection, .. } => {
+                let mut packet = OutgoingPacket::new(buffer.as_slice());
+                connection.send_packet(self.transfer_channel, self.transfer_mode, &mut packet)
+            }
+            _ => Error::OK,
         }
-        Error::OK
     }
 
     fn is_server(&self) -> bool {
@@ -246,6 +633,7 @@ impl IMultiplayerPeerExtension for IrohClient {
 
 impl Drop for IrohClient {
     fn drop(&mut self) {
+        monitors::record_queued(-(self.received_packets.len() as i64));
         self.close();
     }
 }
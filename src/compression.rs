@@ -0,0 +1,212 @@
+//! Compression of the packets sent on a connection.
+//!
+//! Each packet starts with a byte telling how the rest of the packet is compressed.
+//! The nodes exchange the compression modes they can decompress during the handshake,
+//! and a packet is sent uncompressed if the remote node can't decompress it or if
+//! compressing it doesn't make it smaller.
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+
+use crate::config::IrohCompressionMode;
+
+/// The size in bytes of the header added at the start of each packet.
+pub const HEADER_LEN: usize = 1;
+
+/// The size under which packets are not worth compressing.
+const MIN_COMPRESSED_SIZE: usize = 64;
+
+/// The zlib compression level, from 0 to 10.
+const ZLIB_LEVEL: u8 = 6;
+
+/// The set of compression modes a node can decompress.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CompressionModes(u8);
+
+impl CompressionModes {
+    /// The compression modes supported by this version.
+    pub const SUPPORTED: Self =
+        Self(1 << IrohCompressionMode::Lz4 as u8 | 1 << IrohCompressionMode::Zlib as u8);
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, mode: IrohCompressionMode) -> bool {
+        mode == IrohCompressionMode::None || self.0 & (1 << mode as u8) != 0
+    }
+}
+
+/// A packet sent to one or more connections, compressed at most once per compression mode.
+pub struct OutgoingPacket<'a> {
+    packet: &'a [u8],
+    encoded: [Option<Bytes>; 3],
+}
+
+impl<'a> OutgoingPacket<'a> {
+    pub fn new(packet: &'a [u8]) -> Self {
+        Self {
+            packet,
+            encoded: Default::default(),
+        }
+    }
+
+    /// Returns the size of the packet before compression.
+    pub fn size(&self) -> usize {
+        self.packet.len()
+    }
+
+    /// Returns the packet with its header, compressed with the given mode.
+    pub fn encode(&mut self, mode: IrohCompressionMode) -> Bytes {
+        let packet = self.packet;
+        self.encoded[mode as usize]
+            .get_or_insert_with(|| compress(mode, packet).into())
+            .clone()
+    }
+}
+
+/// Compresses a packet with the given mode, returning it with its header.
+fn compress(mode: IrohCompressionMode, packet: &[u8]) -> Vec<u8> {
+    if packet.len() >= MIN_COMPRESSED_SIZE {
+        let compressed = match mode {
+            IrohCompressionMode::None => None,
+            IrohCompressionMode::Lz4 => Some(lz4_flex::compress_prepend_size(packet)),
+            IrohCompressionMode::Zlib => Some(miniz_oxide::deflate::compress_to_vec_zlib(
+                packet, ZLIB_LEVEL,
+            )),
+        };
+        if let Some(compressed) = compressed
+            && compressed.len() < packet.len()
+        {
+            let mut buffer = Vec::with_capacity(HEADER_LEN + compressed.len());
+            buffer.push(mode as u8);
+            buffer.extend_from_slice(&compressed);
+            return buffer;
+        }
+    }
+    let mut buffer = Vec::with_capacity(HEADER_LEN + packet.len());
+    buffer.push(IrohCompressionMode::None as u8);
+    buffer.extend_from_slice(packet);
+    buffer
+}
+
+/// Removes the header of a packet and decompresses it.
+///
+/// Fails if the packet is invalid or if it would be larger than the given size once decompressed.
+pub fn decompress(packet: Bytes, max_size: usize) -> anyhow::Result<Bytes> {
+    let Some(&mode) = packet.first() else {
+        bail!("received an empty packet");
+    };
+    let data = packet.slice(HEADER_LEN..);
+    let packet = match mode {
+        mode if mode == IrohCompressionMode::None as u8 => data,
+        mode if mode == IrohCompressionMode::Lz4 as u8 => {
+            let (size, data) = lz4_flex::block::uncompressed_size(&data)?;
+            if size > max_size {
+                bail!("received a compressed packet larger than the maximum of {max_size} bytes");
+            }
+            lz4_flex::decompress(data, size)?.into()
+        }
+        mode if mode == IrohCompressionMode::Zlib as u8 => {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data, max_size)
+                .map_err(|error| anyhow!("failed to decompress a packet: {error}"))?
+                .into()
+        }
+        mode => bail!("received a packet with an unknown compression mode {mode}"),
+    };
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [IrohCompressionMode; 3] = [
+        IrohCompressionMode::None,
+        IrohCompressionMode::Lz4,
+        IrohCompressionMode::Zlib,
+    ];
+
+    fn compressible_packet() -> Vec<u8> {
+        b"position: 1.0, 2.0, 3.0; ".repeat(40)
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let packets = [Vec::new(), b"small".to_vec(), compressible_packet()];
+        for mode in MODES {
+            for packet in &packets {
+                let encoded = OutgoingPacket::new(packet).encode(mode);
+                let decoded = decompress(encoded, packet.len()).unwrap();
+                assert_eq!(decoded, packet[..], "{mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_large_packets() {
+        let packet = compressible_packet();
+        for mode in [IrohCompressionMode::Lz4, IrohCompressionMode::Zlib] {
+            let encoded = OutgoingPacket::new(&packet).encode(mode);
+            assert_eq!(encoded[0], mode as u8);
+            assert!(encoded.len() < packet.len());
+        }
+    }
+
+    #[test]
+    fn sends_small_and_incompressible_packets_uncompressed() {
+        let incompressible: Vec<u8> = (0..256u32)
+            .map(|index| (index.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        for packet in [b"small".to_vec(), incompressible] {
+            for mode in MODES {
+                let encoded = OutgoingPacket::new(&packet).encode(mode);
+                assert_eq!(encoded[0], IrohCompressionMode::None as u8);
+                assert_eq!(encoded[HEADER_LEN..], packet[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_once_per_mode() {
+        let packet = compressible_packet();
+        let mut outgoing = OutgoingPacket::new(&packet);
+        let first = outgoing.encode(IrohCompressionMode::Lz4);
+        let second = outgoing.encode(IrohCompressionMode::Lz4);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+    }
+
+    #[test]
+    fn rejects_corrupt_packets() {
+        assert!(decompress(Bytes::new(), usize::MAX).is_err());
+        assert!(decompress(Bytes::from_static(&[3, 0]), usize::MAX).is_err());
+
+        let packet = compressible_packet();
+        for mode in [IrohCompressionMode::Lz4, IrohCompressionMode::Zlib] {
+            let encoded = OutgoingPacket::new(&packet).encode(mode);
+            let truncated = encoded.slice(..encoded.len() / 2);
+            assert!(decompress(truncated, packet.len()).is_err(), "{mode:?}");
+
+            // Keep the size prepended by lz4 so that only the data is corrupt
+            let mut garbage = encoded.to_vec();
+            garbage[HEADER_LEN + 4..].fill(0xff);
+            assert!(
+                decompress(garbage.into(), packet.len()).is_err(),
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_packets_larger_than_the_maximum() {
+        let packet = compressible_packet();
+        for mode in [IrohCompressionMode::Lz4, IrohCompressionMode::Zlib] {
+            let encoded = OutgoingPacket::new(&packet).encode(mode);
+            assert!(decompress(encoded, packet.len() - 1).is_err(), "{mode:?}");
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+
+use anyhow::bail;
+use godot::global::Error;
+use godot::prelude::*;
+use iroh::discovery::static_provider::StaticProvider;
+use iroh::endpoint::{Builder, TransportConfig, VarInt};
+use iroh::{Endpoint, NodeAddr, RelayMap, RelayMode, RelayUrl, SecretKey};
+
+use crate::{ALPN, ticket};
+
+/// The maximum length of a protocol name negotiated during the TLS handshake.
+const MAX_ALPN_LEN: usize = 255;
+
+/// The settings extracted from an [IrohConfig] that are needed to create an endpoint.
+pub struct EndpointConfig {
+    pub builder: Builder,
+    pub connection: ConnectionConfig,
+}
+
+/// The settings extracted from an [IrohConfig] that are needed to establish connections.
+#[derive(Clone, Default)]
+pub struct ConnectionConfig {
+    pub alpn: Vec<u8>,
+    pub connect_timeout: Option<Duration>,
+    pub game_version: String,
+    pub password: String,
+    pub auth_payload: Vec<u8>,
+    pub reconnect_attempts: u32,
+    pub reconnect_delay: Duration,
+    pub max_packet_size: usize,
+    pub send_queue_size: usize,
+    pub send_queue_policy: IrohSendQueuePolicy,
+    pub compression: IrohCompressionMode,
+}
+
+/// The relay servers used to reach nodes that cannot be connected to directly.
+#[derive(GodotConvert, Var, Export, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[godot(via = i64)]
+pub enum IrohRelayMode {
+    /// Uses the default relay servers provided by n0.
+    #[default]
+    Default = 0,
+    /// Uses the relay servers listed in `relay_urls`.
+    Custom = 1,
+    /// Disables relay servers, only direct connections are possible.
+    Disabled = 2,
+}
+
+/// What happens when a packet is sent while the send queue of its channel is full.
+#[derive(GodotConvert, Var, Export, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[godot(via = i64)]
+pub enum IrohSendQueuePolicy {
+    /// The packet is not sent and `put_packet` returns `ERR_BUSY`.
+    #[default]
+    Busy = 0,
+    /// The oldest packets waiting in the queue are dropped to make room for the packet.
+    ///
+    /// Only applies to unreliable packets: reliable packets are handled as with `Busy`.
+    DropOldest = 1,
+    /// The peer is disconnected.
+    Disconnect = 2,
+}
+
+/// The algorithm used to compress the packets sent on a channel.
+#[derive(GodotConvert, Var, Export, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[godot(via = i64)]
+pub enum IrohCompressionMode {
+    /// Packets are sent uncompressed.
+    #[default]
+    None = 0,
+    /// Fast compression with a moderate ratio, suited to frequent packets.
+    Lz4 = 1,
+    /// Slower compression with a better ratio, suited to large transfers.
+    Zlib = 2,
+}
+
+/// The configuration used when creating an `IrohServer` or an `IrohClient`.
+///
+/// It can be edited in the inspector and saved as a resource file.
+#[derive(GodotClass)]
+#[class(tool, init, base=Resource)]
+pub struct IrohConfig {
+    base: Base<Resource>,
+
+    /// The secret key identifying the node, as returned by `IrohSecretKey.to_bytes`.
+    ///
+    /// A random secret key is generated if it is empty. Any other length than 32 bytes
+    /// makes the configuration invalid.
+    #[export]
+    secret_key: PackedByteArray,
+
+    /// The UDP port used for IPv4, or 0 to use a random port.
+    #[export(range = (0.0, 65535.0))]
+    bind_port_ipv4: i32,
+
+    /// The UDP port used for IPv6, or 0 to use a random port.
+    #[export(range = (0.0, 65535.0))]
+    bind_port_ipv6: i32,
+
+    /// Identifies the game, appended to the protocol name negotiated with other nodes.
+    ///
+    /// Only nodes with the same application id can connect to each other, so that
+    /// different games using this extension can't connect to each other by mistake.
+    #[export]
+    app_id: GString,
+
+    /// Publishes and resolves node addresses using the n0 DNS servers.
+    ///
+    /// This requires an internet connection.
+    #[export]
+    #[init(val = true)]
+    discovery_n0: bool,
+
+    /// Publishes and resolves node addresses on the local network using mDNS.
+    ///
+    /// This allows nodes to find each other without an internet connection.
+    #[export]
+    pub(crate) discovery_local_network: bool,
+
+    /// Connection strings of nodes whose addresses are known in advance, which must all be
+    /// valid.
+    #[export]
+    static_nodes: PackedStringArray,
+
+    /// The relay servers used to reach nodes that cannot be connected to directly.
+    #[export]
+    relay_mode: IrohRelayMode,
+
+    /// Urls of the relay servers used when `relay_mode` is `RELAY_MODE_CUSTOM`, which needs
+    /// at least one valid url.
+    #[export]
+    relay_urls: PackedStringArray,
+
+    /// The version of the game, clients can only connect to servers with the same version.
+    #[export]
+    game_version: GString,
+
+    /// The password of the server, or the password sent by the client when connecting.
+    ///
+    /// Clients can only connect to a server with a password if they give the same password.
+    /// The password itself is never sent over the network.
+    #[export]
+    password: GString,
+
+    /// Data sent by the client when connecting, such as an account token or an invite code.
+    ///
+    /// The server receives it in its `peer_authenticating` signal.
+    #[export]
+    auth_payload: PackedByteArray,
+
+    /// Time in seconds after which connecting to a node fails, or 0 to wait indefinitely.
+    #[export(range = (0.0, 120.0, or_greater, suffix = "s"))]
+    #[init(val = 15.0)]
+    connect_timeout: f64,
+
+    /// Time in seconds without any activity after which a connection is closed.
+    #[export(range = (0.1, 120.0, or_greater, suffix = "s"))]
+    #[init(val = 30.0)]
+    idle_timeout: f64,
+
+    /// Interval in seconds between the keep alive packets sent to keep connections open.
+    #[export(range = (0.1, 60.0, or_greater, suffix = "s"))]
+    #[init(val = 1.0)]
+    keep_alive_interval: f64,
+
+    /// The maximum number of reliable channels a peer can open.
+    #[export(range = (1.0, 1000.0, or_greater))]
+    #[init(val = 100)]
+    max_reliable_channels: i32,
+
+    /// The maximum size in bytes of a reliable packet.
+    ///
+    /// Larger packets can't be sent, and a connection receiving one is closed.
+    /// The server and its clients should use the same maximum.
+    #[export(range = (1024.0, 268435456.0, or_greater, suffix = "B"))]
+    #[init(val = 16777216)]
+    pub(crate) max_packet_size: i32,
+
+    /// The maximum number of bytes waiting to be sent on each channel of a peer.
+    ///
+    /// Packets queue up when they are sent faster than the peer can receive them.
+    /// Unreliable packets of all channels share a single queue of this size.
+    #[export(range = (1024.0, 268435456.0, or_greater, suffix = "B"))]
+    #[init(val = 33554432)]
+    send_queue_size: i32,
+
+    /// What happens when a packet is sent while the send queue of its channel is full.
+    #[export]
+    send_queue_policy: IrohSendQueuePolicy,
+
+    /// The compression applied to the packets of the channels whose compression
+    /// has not been set with `set_channel_compression`.
+    #[export]
+    pub(crate) compression: IrohCompressionMode,
+
+    /// The size in bytes of the buffers holding unreliable packets waiting to be sent or read.
+    #[export(range = (1024.0, 16777216.0, or_greater, suffix = "B"))]
+    #[init(val = 1048576)]
+    datagram_buffer_size: i32,
+
+    /// The maximum number of peers connected to a server, or 0 for no limit.
+    ///
+    /// Clients connecting to a full server are refused.
+    #[export(range = (0.0, 4096.0, or_greater))]
+    pub(crate) max_peers: i32,
+
+    /// The number of times a client tries to reconnect after losing its connection,
+    /// or 0 to disable reconnection.
+    #[export(range = (0.0, 100.0, or_greater))]
+    reconnect_attempts: i32,
+
+    /// Time in seconds before the first reconnection attempt, doubled after each failed attempt.
+    #[export(range = (0.1, 30.0, or_greater, suffix = "s"))]
+    #[init(val = 1.0)]
+    reconnect_delay: f64,
+
+    /// Time in seconds during which a server keeps the peer id of a client that lost its
+    /// connection, so that it can reconnect as the same peer, or 0 to disable it.
+    #[export(range = (0.0, 300.0, or_greater, suffix = "s"))]
+    pub(crate) reconnect_grace_period: f64,
+}
+
+#[godot_api]
+impl IrohConfig {
+    /// Checks that the configuration can be used to start a server or a client.
+    ///
+    /// Returns `ERR_INVALID_PARAMETER` and prints the problem if it can't, in which case
+    /// the server fails to start and the client fails to connect.
+    #[func]
+    fn validate(&self) -> Error {
+        match self.check() {
+            Ok(()) => Error::OK,
+            Err(error) => {
+                godot_error!("{error}");
+                Error::ERR_INVALID_PARAMETER
+            }
+        }
+    }
+
+    /// Uses the default relay servers provided by n0.
+    #[constant]
+    const RELAY_MODE_DEFAULT: i64 = IrohRelayMode::Default as i64;
+
+    /// Uses the relay servers listed in `relay_urls`.
+    #[constant]
+    const RELAY_MODE_CUSTOM: i64 = IrohRelayMode::Custom as i64;
+
+    /// Disables relay servers, only direct connections are possible.
+    #[constant]
+    const RELAY_MODE_DISABLED: i64 = IrohRelayMode::Disabled as i64;
+
+    /// The packet is not sent and `put_packet` returns `ERR_BUSY`.
+    #[constant]
+    const SEND_QUEUE_POLICY_BUSY: i64 = IrohSendQueuePolicy::Busy as i64;
+
+    /// The oldest packets waiting in the queue are dropped to make room for the packet.
+    ///
+    /// Only applies to unreliable packets: `put_packet` returns `ERR_BUSY` for reliable ones.
+    #[constant]
+    const SEND_QUEUE_POLICY_DROP_OLDEST: i64 = IrohSendQueuePolicy::DropOldest as i64;
+
+    /// The peer is disconnected.
+    #[constant]
+    const SEND_QUEUE_POLICY_DISCONNECT: i64 = IrohSendQueuePolicy::Disconnect as i64;
+
+    /// Packets are sent uncompressed.
+    #[constant]
+    const COMPRESSION_NONE: i64 = IrohCompressionMode::None as i64;
+
+    /// Fast compression with a moderate ratio, suited to frequent packets.
+    #[constant]
+    const COMPRESSION_LZ4: i64 = IrohCompressionMode::Lz4 as i64;
+
+    /// Slower compression with a better ratio, suited to large transfers.
+    #[constant]
+    const COMPRESSION_ZLIB: i64 = IrohCompressionMode::Zlib as i64;
+}
+
+impl IrohConfig {
+    /// Returns the endpoint settings configured from this resource.
+    ///
+    /// Fails if the configuration is invalid, see [Self::validate].
+    pub(crate) fn endpoint_config(&self) -> anyhow::Result<EndpointConfig> {
+        self.check()?;
+        let alpn = self.alpn();
+        let mut builder = Endpoint::builder()
+            .alpns(vec![alpn.clone()])
+            .relay_mode(self.relay_mode()?)
+            .transport_config(self.transport_config());
+        if let Ok(bytes) = <[u8; 32]>::try_from(self.secret_key.as_slice()) {
+            builder = builder.secret_key(SecretKey::from_bytes(&bytes));
+        }
+        if self.bind_port_ipv4 != 0 {
+            let port = self.bind_port_ipv4.clamp(0, u16::MAX as i32) as u16;
+            builder = builder.bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+        }
+        if self.bind_port_ipv6 != 0 {
+            let port = self.bind_port_ipv6.clamp(0, u16::MAX as i32) as u16;
+            builder = builder.bind_addr_v6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0));
+        }
+        if self.discovery_n0 {
+            builder = builder.discovery_n0();
+        }
+        if self.discovery_local_network {
+            builder = builder.discovery_local_network();
+        }
+        if !self.static_nodes.is_empty() {
+            let static_provider = StaticProvider::new();
+            for node_addr in self.static_nodes()? {
+                static_provider.add_node_info(node_addr);
+            }
+            builder = builder.add_discovery(static_provider);
+        }
+        Ok(EndpointConfig {
+            builder,
+            connection: ConnectionConfig {
+                alpn,
+                connect_timeout: (self.connect_timeout > 0.0)
+                    .then(|| Duration::from_secs_f64(self.connect_timeout)),
+                game_version: self.game_version.to_string(),
+                password: self.password.to_string(),
+                auth_payload: self.auth_payload.to_vec(),
+                reconnect_attempts: self.reconnect_attempts.max(0) as u32,
+                reconnect_delay: Duration::from_secs_f64(self.reconnect_delay.max(0.1)),
+                max_packet_size: self.max_packet_size.max(0) as usize,
+                send_queue_size: self.send_queue_size.max(0) as usize,
+                send_queue_policy: self.send_queue_policy,
+                compression: self.compression,
+            },
+        })
+    }
+
+    /// Returns an error describing the first invalid setting, if any.
+    fn check(&self) -> anyhow::Result<()> {
+        if !self.secret_key.is_empty() && self.secret_key.len() != 32 {
+            bail!(
+                "invalid secret key: expected 32 bytes, got {}",
+                self.secret_key.len()
+            );
+        }
+        if self.alpn().len() > MAX_ALPN_LEN {
+            bail!(
+                "invalid app id: expected at most {} bytes",
+                MAX_ALPN_LEN - ALPN.len() - 1
+            );
+        }
+        self.relay_mode()?;
+        self.static_nodes()?;
+        Ok(())
+    }
+
+    /// Returns the protocol name negotiated with other nodes, `godot-iroh/<version>/<app_id>`.
+    fn alpn(&self) -> Vec<u8> {
+        let mut alpn = ALPN.to_vec();
+        if !self.app_id.is_empty() {
+            alpn.push(b'/');
+            alpn.extend_from_slice(self.app_id.to_string().as_bytes());
+        }
+        alpn
+    }
+
+    fn transport_config(&self) -> TransportConfig {
+        let mut transport_config = TransportConfig::default();
+        let idle_timeout = Duration::from_secs_f64(self.idle_timeout.max(0.1));
+        transport_config
+            .max_idle_timeout(Some(
+                VarInt::from_u64(idle_timeout.as_millis() as u64)
+                    .unwrap_or(VarInt::MAX)
+                    .into(),
+            ))
+            .keep_alive_interval(Some(Duration::from_secs_f64(
+                self.keep_alive_interval.max(0.1),
+            )))
+            .max_concurrent_uni_streams(VarInt::from_u32(self.max_reliable_channels.max(1) as u32))
+            .datagram_receive_buffer_size(Some(self.datagram_buffer_size.max(0) as usize))
+            .datagram_send_buffer_size(self.datagram_buffer_size.max(0) as usize);
+        transport_config
+    }
+
+    fn static_nodes(&self) -> anyhow::Result<Vec<NodeAddr>> {
+        let mut node_addrs = Vec::new();
+        for connection_string in self.static_nodes.as_slice() {
+            match ticket::decode(&connection_string.to_string()) {
+                Ok(node_addr) => node_addrs.push(node_addr),
+                Err(error) => bail!("invalid static node {connection_string}: {error}"),
+            }
+        }
+        Ok(node_addrs)
+    }
+
+    fn relay_mode(&self) -> anyhow::Result<RelayMode> {
+        Ok(match self.relay_mode {
+            IrohRelayMode::Default => RelayMode::Default,
+            IrohRelayMode::Custom => {
+                if self.relay_urls.is_empty() {
+                    bail!("invalid relay urls: the custom relay mode needs at least one url");
+                }
+                let mut relay_urls = Vec::new();
+                for relay_url in self.relay_urls.as_slice() {
+                    match relay_url.to_string().parse::<RelayUrl>() {
+                        Ok(relay_url) => relay_urls.push(relay_url),
+                        Err(error) => bail!("invalid relay url {relay_url}: {error}"),
+                    }
+                }
+                RelayMode::Custom(RelayMap::from_iter(relay_urls))
+            }
+            IrohRelayMode::Disabled => RelayMode::Disabled,
+        })
+    }
+}
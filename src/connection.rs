@@ -1,33 +1,46 @@
 use std::collections::{HashMap, hash_map::Entry};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
-use anyhow::{Context, bail};
-use base64::prelude::*;
-use bytes::{Buf, Bytes};
-use godot::{classes::multiplayer_peer::TransferMode, global::godot_error, prelude::godot_warn};
+use bytes::Bytes;
+use godot::{
+    builtin::Dictionary,
+    classes::multiplayer_peer::TransferMode,
+    global::{Error, godot_error},
+    prelude::godot_warn,
+};
 use iroh::{
-    Endpoint, NodeId,
-    endpoint::{Connection, VarInt},
+    Endpoint, NodeAddr, NodeId, Watcher,
+    endpoint::{ConnectError, Connection, ConnectionType, SendDatagramError},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::mpsc::{Receiver, UnboundedSender, channel, error::TryRecvError, unbounded_channel},
+    sync::mpsc::{
+        Receiver, Sender, UnboundedReceiver, channel, error::TryRecvError, unbounded_channel,
+    },
+    time::timeout,
 };
 
-use crate::{ALPN, IrohRuntime};
+use crate::compression::{self, CompressionModes, OutgoingPacket};
+use crate::config::{ConnectionConfig, EndpointConfig, IrohCompressionMode, IrohSendQueuePolicy};
+use crate::disconnect::DisconnectReason;
+use crate::fragmentation::{self, Reassembler, TRAILER_LEN, Trailer};
+use crate::framing::PacketReader;
+use crate::handshake::AuthRequest;
+use crate::send_queue::SendQueue;
+use crate::stats::{ChannelStats, stats_dictionary};
+use crate::{IrohRuntime, framing, handshake, monitors, ticket};
 
 pub struct IrohListener {
     pub(crate) endpoint: Endpoint,
+    pub(crate) config: ConnectionConfig,
     connection_receiver: Receiver<Connection>,
     closed: bool,
 }
 
 impl IrohListener {
-    pub async fn new() -> anyhow::Result<Self> {
-        let endpoint = Endpoint::builder()
-            .alpns(vec![ALPN.to_vec()])
-            .discovery_n0()
-            .bind()
-            .await?;
+    pub async fn new(config: EndpointConfig) -> anyhow::Result<Self> {
+        let endpoint = config.builder.bind().await?;
 
         // Accept connection loop
         let endpoint_clone = endpoint.clone();
@@ -46,6 +59,7 @@ impl IrohListener {
         // Return the listener
         Ok(Self {
             endpoint,
+            config: config.connection,
             connection_receiver,
             closed: false,
         })
@@ -62,7 +76,12 @@ impl IrohListener {
     }
 
     pub fn connection_string(&self) -> String {
-        BASE64_URL_SAFE_NO_PAD.encode(self.endpoint.node_id().as_bytes())
+        let node_addr = self
+            .endpoint
+            .node_addr()
+            .get()
+            .unwrap_or_else(|| NodeAddr::new(self.endpoint.node_id()));
+        ticket::encode(&node_addr)
     }
 
     pub fn receive_connection(&mut self) -> Result<Connection, TryRecvError> {
@@ -77,99 +96,177 @@ impl IrohListener {
     }
 }
 
+/// A reliable channel, whose packets are sent on their own stream.
+struct ReliableChannel {
+    /// The packets waiting to be sent, with their size before compression.
+    queue: Arc<SendQueue<(Bytes, usize)>>,
+    priority: Arc<AtomicI32>,
+}
+
 pub struct IrohConnection {
     connection: Connection,
-    reliable_channels: HashMap<i32, UnboundedSender<Bytes>>,
-    unreliable_sender: UnboundedSender<(i32, bool, Vec<u8>)>,
-    packet_receiver: Receiver<(i32, TransferMode, Bytes)>,
+    reliable_channels: HashMap<i32, ReliableChannel>,
+    channel_priorities: HashMap<i32, i32>,
+    compression: IrohCompressionMode,
+    channel_compression: HashMap<i32, IrohCompressionMode>,
+    remote_compression_modes: CompressionModes,
+    unreliable_queue: Arc<SendQueue<(i32, bool, Bytes, usize)>>,
+    /// The received packets, with their size before decompression.
+    packet_receiver: Receiver<(i32, TransferMode, Bytes, usize)>,
+    max_packet_size: usize,
+    send_queue_size: usize,
+    send_queue_policy: IrohSendQueuePolicy,
+    close_reason: OnceLock<DisconnectReason>,
+    /// Shared with the send tasks, which count the packets once taken from the queues.
+    channel_stats: Arc<Mutex<HashMap<i32, ChannelStats>>>,
+    connection_type: ConnectionType,
+    connection_type_receiver: UnboundedReceiver<ConnectionType>,
 }
 
 impl IrohConnection {
-    async fn new(connection: Connection) -> Self {
-        let (unreliable_sender, mut unreliable_receiver) =
-            unbounded_channel::<(i32, bool, Vec<u8>)>();
+    async fn new(
+        endpoint: &Endpoint,
+        connection: Connection,
+        remote_compression_modes: CompressionModes,
+        config: &ConnectionConfig,
+    ) -> Self {
+        let unreliable_queue = Arc::new(SendQueue::<(i32, bool, Bytes, usize)>::default());
+        let channel_stats = Arc::new(Mutex::new(HashMap::new()));
         let (packet_sender, packet_receiver) = channel(32);
+        let (connection_type_sender, connection_type_receiver) = unbounded_channel();
+        monitors::add_connection(&connection);
+
+        // Connection type watch loop
+        if let Ok(node_id) = connection.remote_node_id()
+            && let Some(mut watcher) = endpoint.conn_type(node_id)
+        {
+            let connection_clone = connection.clone();
+            tokio::spawn(async move {
+                let _ = connection_type_sender.send(watcher.get());
+                loop {
+                    tokio::select! {
+                        _ = connection_clone.closed() => break,
+                        connection_type = watcher.updated() => {
+                            let Ok(connection_type) = connection_type else {
+                                break;
+                            };
+                            if connection_type_sender.send(connection_type).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
 
         // Unreliable packet send loop
         let connection_clone = connection.clone();
+        let unreliable_queue_clone = unreliable_queue.clone();
+        let channel_stats_clone = channel_stats.clone();
         tokio::spawn(async move {
             let mut last_counts = HashMap::new();
-            while let Some((channel, ordered, mut buffer)) = unreliable_receiver.recv().await {
-                buffer.extend_from_slice(&channel.to_be_bytes());
-                if ordered {
+            let mut last_packet_id = 0u32;
+            while let Some((channel, ordered, buffer, size)) = unreliable_queue_clone.pop().await {
+                let count = if ordered {
                     let count = last_counts.entry(channel).or_insert(0u32);
                     *count = count.wrapping_add(1);
                     if *count == 0 {
                         *count += 1;
                     }
-                    buffer.extend_from_slice(&count.to_be_bytes());
+                    *count
                 } else {
-                    buffer.extend_from_slice(&0u32.to_be_bytes());
-                }
-                let max_datagram_size = connection_clone.max_datagram_size().unwrap_or(1024);
-                if buffer.len() > max_datagram_size {
-                    godot_warn!(
-                        "Unreliable packet on channel {} (size: {}) exceeds {} bytes and will likely be discarded by the network",
+                    0
+                };
+                let fragment_size =
+                    fragmentation::fragment_size(connection_clone.max_datagram_size());
+                let fragment_count = buffer.len().div_ceil(fragment_size).max(1);
+                if fragment_count > fragmentation::MAX_FRAGMENTS {
+                    godot_error!(
+                        "Unreliable packet on channel {} (size: {}) exceeds the maximum allowed size of {} bytes and cannot be sent",
                         channel,
                         buffer.len(),
-                        max_datagram_size,
+                        fragment_size * fragmentation::MAX_FRAGMENTS,
                     );
+                    continue;
                 }
-                if connection_clone.send_datagram(buffer.into()).is_err() {
-                    break;
-                }
-            }
-        });
-
-        // Unreliable packet receive loop
-        let connection_clone = connection.clone();
-        let packet_sender_clone = packet_sender.clone();
-        tokio::spawn(async move {
-            let mut last_counts = HashMap::new();
-            while let Ok(mut packet) = connection_clone.read_datagram().await {
-                if packet.len() < 8 {
-                    break;
-                }
-                let count = packet.split_off(packet.len() - 4).get_u32();
-                let channel = packet.split_off(packet.len() - 4).get_i32();
-                let mode: TransferMode;
-
-                // Ignore packets from the past if in ordered mode
-                if count != 0 {
-                    mode = TransferMode::UNRELIABLE_ORDERED;
-                    let last_count = last_counts.entry(channel).or_insert(0u32);
-                    if count < *last_count && *last_count - count < (u32::MAX / 4) {
-                        continue;
-                    }
-                    *last_count = count;
+                let mode = if ordered {
+                    TransferMode::UNRELIABLE_ORDERED
                 } else {
-                    mode = TransferMode::UNRELIABLE;
-                }
+                    TransferMode::UNRELIABLE
+                };
+                record_sent(&channel_stats_clone, channel, mode, size, buffer.len());
+                last_packet_id = last_packet_id.wrapping_add(1);
+                let mut trailer = Trailer {
+                    channel,
+                    count,
+                    packet_id: last_packet_id,
+                    fragment_index: 0,
+                    fragment_count: fragment_count as u8,
+                };
 
-                // Send the packet to the main thread
-                if packet_sender_clone
-                    .send((channel, mode, packet))
-                    .await
-                    .is_err()
-                {
-                    break;
+                // The buffer may be shared with other connections, so the
+                // trailer is added to a copy of each fragment
+                let fragments = buffer.chunks(fragment_size);
+                for (index, fragment) in fragments.enumerate() {
+                    let mut datagram = Vec::with_capacity(fragment.len() + TRAILER_LEN);
+                    datagram.extend_from_slice(fragment);
+                    trailer.fragment_index = index as u8;
+                    trailer.write(&mut datagram);
+                    match connection_clone.send_datagram(datagram.into()) {
+                        Ok(()) => {}
+                        Err(SendDatagramError::ConnectionLost(_)) => return,
+                        Err(error) => godot_warn!(
+                            "Failed to send an unreliable packet on channel {}: {}",
+                            channel,
+                            error,
+                        ),
+                    }
                 }
             }
         });
 
+        // Unreliable packet receive loop
+        let max_packet_size = config.max_packet_size;
+        tokio::spawn(receive_datagrams(
+            connection.clone(),
+            packet_sender.clone(),
+            max_packet_size,
+        ));
+
         // Reliable channel receive loop
         let connection_clone = connection.clone();
         tokio::spawn(async move {
             while let Ok(mut stream) = connection_clone.accept_uni().await {
+                let connection = connection_clone.clone();
                 let packet_sender = packet_sender.clone();
                 tokio::spawn(async move {
                     let channel = stream.read_i32().await?;
+                    let mut reader = PacketReader::default();
                     loop {
-                        let packet_len = stream.read_u16().await?;
-                        let mut packet = vec![0u8; packet_len as usize];
-                        stream.read_exact(&mut packet).await?;
+                        let max_size = max_packet_size + compression::HEADER_LEN;
+                        let packet = match reader.read_packet(&mut stream, max_size).await {
+                            Ok(packet) => packet,
+                            Err(error) => {
+                                if let Some(reason) = error.downcast_ref::<DisconnectReason>() {
+                                    reason.close(&connection);
+                                }
+                                return Err(error);
+                            }
+                        };
+                        let size = packet.len();
+                        let packet = match compression::decompress(packet, max_packet_size) {
+                            Ok(packet) => packet,
+                            Err(error) => {
+                                DisconnectReason::new(
+                                    DisconnectReason::PROTOCOL_ERROR,
+                                    error.to_string(),
+                                )
+                                .close(&connection);
+                                return Err(error);
+                            }
+                        };
                         if packet_sender
-                            .send((channel, TransferMode::RELIABLE, packet.into()))
+                            .send((channel, TransferMode::RELIABLE, packet, size))
                             .await
                             .is_err()
                         {
@@ -185,89 +282,409 @@ impl IrohConnection {
         Self {
             connection,
             reliable_channels: HashMap::new(),
-            unreliable_sender,
+            channel_priorities: HashMap::new(),
+            compression: config.compression,
+            channel_compression: HashMap::new(),
+            remote_compression_modes,
+            unreliable_queue,
             packet_receiver,
+            max_packet_size: config.max_packet_size,
+            send_queue_size: config.send_queue_size,
+            send_queue_policy: config.send_queue_policy,
+            close_reason: OnceLock::new(),
+            channel_stats,
+            connection_type: ConnectionType::None,
+            connection_type_receiver,
         }
     }
 
-    pub async fn accept(connection: Connection, peer_id: i32) -> anyhow::Result<Self> {
-        connection.open_uni().await?.write_i32(peer_id).await?;
-        Ok(Self::new(connection).await)
+    pub async fn accept(
+        endpoint: Endpoint,
+        connection: Connection,
+        peer_id: i32,
+        resumable_peer_id: Option<i32>,
+        config: &ConnectionConfig,
+        authenticator: Option<Sender<AuthRequest>>,
+    ) -> anyhow::Result<(i32, Self)> {
+        let handshake = handshake::accept(
+            &connection,
+            peer_id,
+            resumable_peer_id,
+            config,
+            authenticator.as_ref(),
+        );
+        let (peer_id, compression_modes) = match config.connect_timeout {
+            Some(connect_timeout) => match timeout(connect_timeout, handshake).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    let reason = DisconnectReason::from_code(DisconnectReason::TIMEOUT);
+                    reason.close(&connection);
+                    return Err(reason.into());
+                }
+            },
+            None => handshake.await?,
+        };
+        let connection = Self::new(&endpoint, connection, compression_modes, config).await;
+        Ok((peer_id, connection))
     }
 
     pub async fn connect(
         endpoint: Endpoint,
+        config: &ConnectionConfig,
         connection_string: String,
+        resumed_peer_id: Option<i32>,
     ) -> anyhow::Result<(i32, Self)> {
-        let node_id_bytes = BASE64_URL_SAFE_NO_PAD
-            .decode(connection_string)
-            .context("invalid connection string")?;
-        let node_id_bytes: [u8; 32] = match node_id_bytes.try_into() {
-            Ok(bytes) => bytes,
-            Err(_) => bail!("invalid connection string"),
+        let node_addr = ticket::decode(&connection_string)?;
+        let handshake = async {
+            let connection = endpoint
+                .connect(node_addr, &config.alpn)
+                .await
+                .map_err(|error| match error {
+                    ConnectError::Connection { source, .. } => {
+                        DisconnectReason::from_connection_error(&source).into()
+                    }
+                    error => anyhow::Error::from(error),
+                })?;
+            let (peer_id, compression_modes) =
+                handshake::connect(&connection, config, resumed_peer_id).await?;
+            Ok::<_, anyhow::Error>((peer_id, compression_modes, connection))
+        };
+        let (peer_id, compression_modes, connection) = match config.connect_timeout {
+            Some(connect_timeout) => timeout(connect_timeout, handshake)
+                .await
+                .map_err(|_| DisconnectReason::from_code(DisconnectReason::TIMEOUT))??,
+            None => handshake.await?,
         };
-        let node_id = NodeId::from_bytes(&node_id_bytes).context("invalid connection string")?;
-        let connection = endpoint.connect(node_id, ALPN).await?;
-        let peer_id = connection.accept_uni().await?.read_i32().await?;
-        Ok((peer_id, Self::new(connection).await))
+        let connection = Self::new(&endpoint, connection, compression_modes, config).await;
+        Ok((peer_id, connection))
     }
 
     pub fn close(&self) {
-        self.connection.close(VarInt::from_u32(0), b"");
+        self.close_with_reason(&DisconnectReason::from_code(DisconnectReason::CLOSED));
     }
 
-    pub fn send_packet(&mut self, channel: i32, mode: TransferMode, packet: Vec<u8>) {
-        if mode == TransferMode::RELIABLE {
-            let sender = match self.reliable_channels.entry(channel) {
+    pub fn close_with_reason(&self, reason: &DisconnectReason) {
+        let _ = self.close_reason.set(reason.clone());
+        reason.close(&self.connection);
+    }
+
+    /// Returns the reason why the connection has been closed.
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        if let Some(reason) = self.close_reason.get() {
+            return reason.clone();
+        }
+        match self.connection.close_reason() {
+            Some(error) => DisconnectReason::from_connection_error(&error),
+            None => DisconnectReason::from_code(DisconnectReason::CLOSED),
+        }
+    }
+
+    /// Queues a packet to be sent on the given channel.
+    ///
+    /// Returns `ERR_INVALID_PARAMETER` if the packet is larger than the maximum size of
+    /// its transfer mode, and `ERR_BUSY` if the send queue of the channel is full, in
+    /// which case the connection is closed if the send queue policy says so.
+    pub fn send_packet(
+        &mut self,
+        channel: i32,
+        mode: TransferMode,
+        packet: &mut OutgoingPacket,
+    ) -> Error {
+        let size = packet.size();
+        if mode == TransferMode::RELIABLE && size > self.max_packet_size {
+            godot_error!(
+                "Reliable packet on channel {} (size: {}) exceeds the maximum allowed size of {} bytes and cannot be sent",
+                channel,
+                size,
+                self.max_packet_size,
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        if mode != TransferMode::RELIABLE && size > self.max_unreliable_packet_size() {
+            godot_error!(
+                "Unreliable packet on channel {} (size: {}) exceeds the maximum allowed size of {} bytes and cannot be sent",
+                channel,
+                size,
+                self.max_unreliable_packet_size(),
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        let mut compression = self
+            .channel_compression
+            .get(&channel)
+            .copied()
+            .unwrap_or(self.compression);
+        if !self.remote_compression_modes.contains(compression) {
+            compression = IrohCompressionMode::None;
+        }
+        let packet = packet.encode(compression);
+        let compressed_size = packet.len();
+
+        // Reliable packets are never dropped, since the remote node relies on receiving them
+        let drop_oldest = self.send_queue_policy == IrohSendQueuePolicy::DropOldest;
+        let queued = if mode == TransferMode::RELIABLE {
+            let reliable_channel = match self.reliable_channels.entry(channel) {
                 Entry::Occupied(entry) => entry.into_mut(),
                 Entry::Vacant(entry) => {
                     let connection = self.connection.clone();
-                    let (sender, mut receiver) = unbounded_channel::<Bytes>();
+                    let queue = Arc::new(SendQueue::<(Bytes, usize)>::default());
+                    let queue_clone = queue.clone();
+                    let channel_stats = self.channel_stats.clone();
+                    let priority = Arc::new(AtomicI32::new(
+                        self.channel_priorities.get(&channel).copied().unwrap_or(0),
+                    ));
+                    let priority_clone = priority.clone();
                     IrohRuntime::spawn(async move {
                         let mut stream = connection.open_uni().await?;
+                        let mut stream_priority = priority_clone.load(Ordering::Relaxed);
+                        stream.set_priority(stream_priority)?;
                         stream.write_i32(channel).await?;
-                        while let Some(packet) = receiver.recv().await {
-                            if packet.len() > u16::MAX as usize {
-                                godot_error!(
-                                    "Reliable packet on channel {} (size: {}) exceeds the maximum allowed size of {} bytes and cannot be sent",
-                                    channel,
-                                    packet.len(),
-                                    u16::MAX,
-                                );
-                            } else {
-                                stream.write_u16(packet.len().try_into()?).await?;
-                                stream.write_all(&packet).await?;
+                        while let Some((packet, size)) = queue_clone.pop().await {
+                            record_sent(
+                                &channel_stats,
+                                channel,
+                                TransferMode::RELIABLE,
+                                size,
+                                packet.len(),
+                            );
+                            // Apply the priority changed since the last packet
+                            let priority = priority_clone.load(Ordering::Relaxed);
+                            if priority != stream_priority {
+                                stream.set_priority(priority)?;
+                                stream_priority = priority;
                             }
+                            framing::write_packet(&mut stream, packet).await?;
                         }
 
                         Ok::<(), anyhow::Error>(())
                     });
-                    entry.insert(sender)
+                    entry.insert(ReliableChannel { queue, priority })
                 }
             };
-            let _ = sender.send(packet.into());
+            reliable_channel.queue.push(
+                (packet, size),
+                compressed_size,
+                self.send_queue_size,
+                false,
+            )
         } else {
-            let _ = self.unreliable_sender.send((
-                channel,
-                mode == TransferMode::UNRELIABLE_ORDERED,
-                packet,
-            ));
+            let ordered = mode == TransferMode::UNRELIABLE_ORDERED;
+            self.unreliable_queue.push(
+                (channel, ordered, packet, size),
+                compressed_size,
+                self.send_queue_size,
+                drop_oldest,
+            )
+        };
+        if !queued {
+            if self.send_queue_policy == IrohSendQueuePolicy::Disconnect {
+                self.close_with_reason(&DisconnectReason::from_code(
+                    DisconnectReason::SEND_QUEUE_FULL,
+                ));
+            }
+            return Error::ERR_BUSY;
         }
+        Error::OK
     }
 
     pub fn receive_packet(&mut self) -> Result<(i32, TransferMode, Bytes), TryRecvError> {
-        self.packet_receiver.try_recv()
+        let (channel, mode, packet, compressed_size) = self.packet_receiver.try_recv()?;
+        let mut channel_stats = self.channel_stats.lock().unwrap();
+        let stats = channel_stats.entry(channel).or_default();
+        stats.packets_received += 1;
+        stats.bytes_received += packet.len() as u64;
+        stats.compressed_bytes_received += compressed_size as u64;
+        monitors::record_received(mode, compressed_size);
+        Ok((channel, mode, packet))
     }
 
-    pub fn connection_string(&self) -> String {
+    /// Sets the compression of the packets sent on a channel.
+    pub fn set_channel_compression(&mut self, channel: i32, compression: IrohCompressionMode) {
+        self.channel_compression.insert(channel, compression);
+    }
+
+    /// Returns the statistics of the connection and of each channel.
+    pub fn stats(&self) -> Dictionary {
+        stats_dictionary(
+            &self.connection.stats(),
+            &self.channel_stats.lock().unwrap(),
+        )
+    }
+
+    /// Returns the paths currently used to reach the remote node.
+    pub fn connection_type(&self) -> &ConnectionType {
+        &self.connection_type
+    }
+
+    /// Updates the connection type, returning whether it has changed since the last update.
+    pub fn update_connection_type(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(connection_type) = self.connection_type_receiver.try_recv() {
+            changed |= connection_type != self.connection_type;
+            self.connection_type = connection_type;
+        }
+        changed
+    }
+
+    /// Sets the priority of the stream of a reliable channel.
+    ///
+    /// Streams with a higher priority are sent first when the connection is congested.
+    pub fn set_channel_priority(&mut self, channel: i32, priority: i32) {
+        self.channel_priorities.insert(channel, priority);
+        if let Some(reliable_channel) = self.reliable_channels.get(&channel) {
+            reliable_channel.priority.store(priority, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of bytes waiting to be sent on all the channels.
+    pub fn queued_bytes(&self) -> usize {
+        self.reliable_channels
+            .values()
+            .map(|reliable_channel| reliable_channel.queue.queued_bytes())
+            .sum::<usize>()
+            + self.unreliable_queue.queued_bytes()
+    }
+
+    /// Returns the maximum size of an unreliable packet, which depends on the network path.
+    pub fn max_unreliable_packet_size(&self) -> usize {
+        max_unreliable_packet_size(self.connection.max_datagram_size())
+    }
+
+    pub fn node_id(&self) -> NodeId {
         // If the connection is made the node id should be valid
-        let node_id = self.connection.remote_node_id().unwrap();
-        BASE64_URL_SAFE_NO_PAD.encode(node_id.as_bytes())
+        self.connection.remote_node_id().unwrap()
+    }
+
+    pub fn connection_string(&self) -> String {
+        ticket::encode_node_id(&self.node_id())
     }
 }
 
 impl Drop for IrohConnection {
     fn drop(&mut self) {
+        monitors::remove_connection(&self.connection);
+        for reliable_channel in self.reliable_channels.values() {
+            reliable_channel.queue.close();
+        }
+        self.unreliable_queue.close();
         self.close();
     }
 }
+
+/// Receives the unreliable packets of a connection, reassembling their fragments and
+/// sending them to the main thread along with their size before decompression.
+async fn receive_datagrams(
+    connection: Connection,
+    packet_sender: Sender<(i32, TransferMode, Bytes, usize)>,
+    max_packet_size: usize,
+) {
+    let mut last_counts = HashMap::new();
+    let mut reassembler = Reassembler::default();
+    while let Ok(mut datagram) = connection.read_datagram().await {
+        // Drop the datagrams that are too short or have an invalid trailer
+        let Some(trailer) = Trailer::read(&mut datagram) else {
+            monitors::record_malformed();
+            continue;
+        };
+        let Some(packet) = reassembler.push(trailer, datagram) else {
+            continue;
+        };
+        let channel = trailer.channel;
+        let mode: TransferMode;
+
+        // Ignore packets from the past if in ordered mode
+        if trailer.count != 0 {
+            mode = TransferMode::UNRELIABLE_ORDERED;
+            let count = trailer.count;
+            let last_count = last_counts.entry(channel).or_insert(0u32);
+            if count < *last_count && *last_count - count < (u32::MAX / 4) {
+                monitors::record_dropped();
+                continue;
+            }
+            *last_count = count;
+        } else {
+            mode = TransferMode::UNRELIABLE;
+        }
+
+        // Drop the packets that can't be decompressed
+        let size = packet.len();
+        let Ok(packet) = compression::decompress(packet, max_packet_size) else {
+            continue;
+        };
+
+        // Send the packet to the main thread
+        if packet_sender
+            .send((channel, mode, packet, size))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Returns the maximum size of an unreliable packet given by the game, before its
+/// compression header is added, or the default size if the connection isn't known.
+pub fn max_unreliable_packet_size(max_datagram_size: Option<usize>) -> usize {
+    fragmentation::max_packet_size(max_datagram_size) - compression::HEADER_LEN
+}
+
+/// Counts a packet taken from a send queue in the statistics of its channel.
+fn record_sent(
+    channel_stats: &Mutex<HashMap<i32, ChannelStats>>,
+    channel: i32,
+    mode: TransferMode,
+    size: usize,
+    compressed_size: usize,
+) {
+    let mut channel_stats = channel_stats.lock().unwrap();
+    let stats = channel_stats.entry(channel).or_default();
+    stats.packets_sent += 1;
+    stats.bytes_sent += size as u64;
+    stats.compressed_bytes_sent += compressed_size as u64;
+    monitors::record_sent(mode, compressed_size);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing;
+
+    fn datagram(payload: &[u8], fragment_count: u8) -> Bytes {
+        let mut datagram = payload.to_vec();
+        Trailer {
+            channel: 2,
+            count: 0,
+            packet_id: 1,
+            fragment_index: 0,
+            fragment_count,
+        }
+        .write(&mut datagram);
+        datagram.into()
+    }
+
+    #[tokio::test]
+    async fn skips_malformed_datagrams() {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        let (packet_sender, mut packet_receiver) = channel(8);
+        tokio::spawn(receive_datagrams(remote_connection, packet_sender, 1024));
+
+        connection
+            .send_datagram(Bytes::from_static(b"short"))
+            .unwrap();
+        connection.send_datagram(datagram(b"", 0)).unwrap();
+        let packet = OutgoingPacket::new(b"hello").encode(IrohCompressionMode::None);
+        connection.send_datagram(datagram(&packet, 1)).unwrap();
+
+        let received = timeout(Duration::from_secs(5), packet_receiver.recv())
+            .await
+            .unwrap();
+        let (channel, mode, packet, size) = received.unwrap();
+        assert_eq!(channel, 2);
+        assert_eq!(mode, TransferMode::UNRELIABLE);
+        assert_eq!(packet, Bytes::from_static(b"hello"));
+        assert_eq!(size, compression::HEADER_LEN + 5);
+    }
+}
@@ -0,0 +1,49 @@
+use godot::prelude::*;
+use iroh::endpoint::ConnectionType;
+
+/// Returns the code of the connection type, as listed in [IrohConnectionType].
+pub fn connection_type_code(connection_type: &ConnectionType) -> i32 {
+    match connection_type {
+        ConnectionType::None => IrohConnectionType::NONE,
+        ConnectionType::Direct(_) => IrohConnectionType::DIRECT,
+        ConnectionType::Relay(_) => IrohConnectionType::RELAY,
+        ConnectionType::Mixed(_, _) => IrohConnectionType::MIXED,
+    }
+}
+
+/// Returns the address used to reach the remote node: its UDP address if
+/// known, the url of its relay server otherwise.
+pub fn remote_address(connection_type: &ConnectionType) -> String {
+    match connection_type {
+        ConnectionType::None => String::new(),
+        ConnectionType::Direct(addr) | ConnectionType::Mixed(addr, _) => addr.to_string(),
+        ConnectionType::Relay(relay_url) => relay_url.to_string(),
+    }
+}
+
+/// The paths used by a connection to reach the remote node.
+#[derive(GodotClass)]
+#[class(no_init, base=Object)]
+pub struct IrohConnectionType {
+    base: Base<Object>,
+}
+
+#[godot_api]
+impl IrohConnectionType {
+    /// The remote node can't be reached at the moment.
+    #[constant]
+    pub const NONE: i32 = 0;
+
+    /// Packets are sent directly to the remote node over UDP.
+    #[constant]
+    pub const DIRECT: i32 = 1;
+
+    /// Packets are sent through a relay server.
+    #[constant]
+    pub const RELAY: i32 = 2;
+
+    /// Packets are sent both directly and through a relay server,
+    /// while the direct path is being confirmed.
+    #[constant]
+    pub const MIXED: i32 = 3;
+}
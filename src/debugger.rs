@@ -0,0 +1,53 @@
+//! Messages sent to the debugger panel of the editor plugin.
+
+use std::time::{Duration, Instant};
+
+use godot::classes::EngineDebugger;
+use godot::prelude::*;
+
+use crate::connection::IrohConnection;
+use crate::connection_type::{connection_type_code, remote_address};
+
+/// The interval between two reports sent to the editor.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically sends the statistics of the peers of a multiplayer peer to the editor.
+#[derive(Default)]
+pub struct DebuggerReporter {
+    last_report: Option<Instant>,
+}
+
+impl DebuggerReporter {
+    /// Sends the statistics of the given peers if the game is running
+    /// from the editor and the last report is old enough.
+    ///
+    /// The name of the multiplayer peer is only built when a report is sent.
+    pub fn report<'a>(
+        &mut self,
+        source: impl FnOnce() -> String,
+        peers: impl IntoIterator<Item = (i32, &'a IrohConnection)>,
+    ) {
+        if self
+            .last_report
+            .is_some_and(|last_report| last_report.elapsed() < REPORT_INTERVAL)
+        {
+            return;
+        }
+        self.last_report = Some(Instant::now());
+        let mut debugger = EngineDebugger::singleton();
+        if !debugger.is_active() {
+            return;
+        }
+        let peers: Dictionary = peers
+            .into_iter()
+            .map(|(peer_id, connection)| {
+                let mut stats = connection.stats();
+                let connection_type = connection.connection_type();
+                stats.set("connection_type", connection_type_code(connection_type));
+                stats.set("remote_address", remote_address(connection_type));
+                (peer_id, stats)
+            })
+            .collect();
+        debugger.send_message("godot_iroh:peers", &varray![source(), peers]);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use godot::prelude::*;
+use iroh::endpoint::{Connection, ConnectionError, TransportErrorCode, VarInt};
+
+/// The TLS alert sent when no protocol offered by the client is supported by the server.
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// The number of departed peers whose disconnect reason is remembered by a server.
+const MAX_DISCONNECT_REASONS: usize = 1024;
+
+/// The cause of a closed connection, sent to the remote node when closing it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisconnectReason {
+    pub code: u32,
+    pub reason: String,
+}
+
+impl DisconnectReason {
+    pub const CLOSED: u32 = 0;
+    pub const REFUSED: u32 = 1;
+    pub const SERVER_FULL: u32 = 2;
+    pub const KICKED: u32 = 3;
+    pub const BANNED: u32 = 4;
+    pub const SERVER_SHUTDOWN: u32 = 5;
+    pub const TIMEOUT: u32 = 6;
+    pub const VERSION_MISMATCH: u32 = 7;
+    pub const TRANSPORT_ERROR: u32 = 8;
+    pub const APPLICATION_MISMATCH: u32 = 9;
+    pub const WRONG_PASSWORD: u32 = 10;
+    pub const AUTHENTICATION_FAILED: u32 = 11;
+    pub const SEND_QUEUE_FULL: u32 = 12;
+    pub const PROTOCOL_ERROR: u32 = 13;
+
+    pub fn new(code: u32, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Creates a reason with the default description of the code.
+    pub fn from_code(code: u32) -> Self {
+        Self::new(code, "")
+    }
+
+    /// Returns the reason of a connection that has been closed with the given error.
+    pub fn from_connection_error(error: &ConnectionError) -> Self {
+        match error {
+            ConnectionError::ApplicationClosed(close) => Self::new(
+                close.error_code.into_inner().try_into().unwrap_or(u32::MAX),
+                String::from_utf8_lossy(&close.reason),
+            ),
+            ConnectionError::LocallyClosed => Self::from_code(Self::CLOSED),
+            ConnectionError::TimedOut => Self::from_code(Self::TIMEOUT),
+            // The remote node lost the state of the connection, for example after a restart
+            ConnectionError::Reset => Self::new(Self::TRANSPORT_ERROR, error.to_string()),
+            // The TLS handshake fails with a no_application_protocol alert when the ALPN differs
+            ConnectionError::ConnectionClosed(close)
+                if close.error_code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL) =>
+            {
+                Self::from_code(Self::APPLICATION_MISMATCH)
+            }
+            error => Self::new(Self::PROTOCOL_ERROR, error.to_string()),
+        }
+    }
+
+    /// Returns whether the connection has been lost because of the network,
+    /// rather than closed on purpose by one of the nodes.
+    ///
+    /// Connections closed because a node broke the protocol are not considered lost,
+    /// since reconnecting would fail the same way.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self.code, Self::TIMEOUT | Self::TRANSPORT_ERROR)
+    }
+
+    /// Closes the connection, sending this reason to the remote node.
+    pub fn close(&self, connection: &Connection) {
+        connection.close(VarInt::from_u32(self.code), self.reason.as_bytes());
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.reason.is_empty() {
+            return f.write_str(&self.reason);
+        }
+        f.write_str(match self.code {
+            Self::CLOSED => "the connection was closed",
+            Self::REFUSED => "the server is not accepting new connections",
+            Self::SERVER_FULL => "the server is full",
+            Self::KICKED => "kicked from the server",
+            Self::BANNED => "banned from the server",
+            Self::SERVER_SHUTDOWN => "the server has shut down",
+            Self::TIMEOUT => "the connection timed out",
+            Self::VERSION_MISMATCH => "incompatible version",
+            Self::TRANSPORT_ERROR => "transport error",
+            Self::APPLICATION_MISMATCH => "the remote node belongs to a different application",
+            Self::WRONG_PASSWORD => "wrong password",
+            Self::AUTHENTICATION_FAILED => "the server rejected the authentication",
+            Self::SEND_QUEUE_FULL => "too many packets waiting to be sent",
+            Self::PROTOCOL_ERROR => "the remote node sent invalid data",
+            _ => "the connection was closed by the remote node",
+        })
+    }
+}
+
+impl std::error::Error for DisconnectReason {}
+
+/// The disconnect reasons of the peers that left a server.
+///
+/// Only the most recent reasons are kept, so that a long-running server doesn't
+/// accumulate the reasons of every peer it has ever seen.
+#[derive(Default)]
+pub struct DisconnectReasons {
+    reasons: HashMap<i32, DisconnectReason>,
+    peer_ids: VecDeque<i32>,
+}
+
+impl DisconnectReasons {
+    pub fn get(&self, peer_id: i32) -> Option<&DisconnectReason> {
+        self.reasons.get(&peer_id)
+    }
+
+    /// Sets the reason of a peer, forgetting the oldest reason if there are too many.
+    pub fn insert(&mut self, peer_id: i32, reason: DisconnectReason) {
+        if self.reasons.insert(peer_id, reason).is_some() {
+            return;
+        }
+        self.peer_ids.push_back(peer_id);
+        if self.peer_ids.len() > MAX_DISCONNECT_REASONS
+            && let Some(oldest_peer_id) = self.peer_ids.pop_front()
+        {
+            self.reasons.remove(&oldest_peer_id);
+        }
+    }
+
+    /// Sets the reason of a peer unless it already has one.
+    pub fn insert_if_absent(&mut self, peer_id: i32, reason: DisconnectReason) {
+        if !self.reasons.contains_key(&peer_id) {
+            self.insert(peer_id, reason);
+        }
+    }
+
+    pub fn remove(&mut self, peer_id: i32) {
+        if self.reasons.remove(&peer_id).is_some() {
+            self.peer_ids.retain(|other_id| *other_id != peer_id);
+        }
+    }
+}
+
+/// The codes describing why a connection has been closed.
+///
+/// Codes greater than or equal to [Self::CUSTOM] can be used by the
+/// application to close connections with its own reasons.
+#[derive(GodotClass)]
+#[class(no_init, base=Object)]
+struct IrohDisconnectReason {
+    base: Base<Object>,
+}
+
+#[godot_api]
+impl IrohDisconnectReason {
+    /// The connection was closed normally.
+    #[constant]
+    const CLOSED: i32 = DisconnectReason::CLOSED as i32;
+
+    /// The server is not accepting new connections.
+    #[constant]
+    const REFUSED: i32 = DisconnectReason::REFUSED as i32;
+
+    /// The server has reached its maximum number of peers.
+    #[constant]
+    const SERVER_FULL: i32 = DisconnectReason::SERVER_FULL as i32;
+
+    /// The peer has been kicked by the server.
+    #[constant]
+    const KICKED: i32 = DisconnectReason::KICKED as i32;
+
+    /// The peer has been banned by the server.
+    #[constant]
+    const BANNED: i32 = DisconnectReason::BANNED as i32;
+
+    /// The server has been closed.
+    #[constant]
+    const SERVER_SHUTDOWN: i32 = DisconnectReason::SERVER_SHUTDOWN as i32;
+
+    /// The remote node stopped responding.
+    #[constant]
+    const TIMEOUT: i32 = DisconnectReason::TIMEOUT as i32;
+
+    /// The remote node uses an incompatible version.
+    #[constant]
+    const VERSION_MISMATCH: i32 = DisconnectReason::VERSION_MISMATCH as i32;
+
+    /// The connection was lost because of a network error.
+    #[constant]
+    const TRANSPORT_ERROR: i32 = DisconnectReason::TRANSPORT_ERROR as i32;
+
+    /// The remote node belongs to a different application, see `IrohConfig.app_id`.
+    #[constant]
+    const APPLICATION_MISMATCH: i32 = DisconnectReason::APPLICATION_MISMATCH as i32;
+
+    /// The password given by the client doesn't match the password of the server.
+    #[constant]
+    const WRONG_PASSWORD: i32 = DisconnectReason::WRONG_PASSWORD as i32;
+
+    /// The server rejected the authentication payload of the client.
+    #[constant]
+    const AUTHENTICATION_FAILED: i32 = DisconnectReason::AUTHENTICATION_FAILED as i32;
+
+    /// The peer didn't receive its packets fast enough, see `IrohConfig.send_queue_policy`.
+    #[constant]
+    const SEND_QUEUE_FULL: i32 = DisconnectReason::SEND_QUEUE_FULL as i32;
+
+    /// The remote node sent data that doesn't follow the protocol, such as an oversized packet.
+    #[constant]
+    const PROTOCOL_ERROR: i32 = DisconnectReason::PROTOCOL_ERROR as i32;
+
+    /// The first code available for the reasons defined by the application.
+    #[constant]
+    const CUSTOM: i32 = 1000;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_the_oldest_reasons() {
+        let mut reasons = DisconnectReasons::default();
+        for peer_id in 0..MAX_DISCONNECT_REASONS as i32 + 10 {
+            reasons.insert(
+                peer_id,
+                DisconnectReason::from_code(DisconnectReason::CLOSED),
+            );
+        }
+        assert!(reasons.get(9).is_none());
+        assert!(reasons.get(10).is_some());
+        assert_eq!(reasons.reasons.len(), MAX_DISCONNECT_REASONS);
+        assert_eq!(reasons.peer_ids.len(), MAX_DISCONNECT_REASONS);
+    }
+
+    #[test]
+    fn keeps_the_first_reason() {
+        let mut reasons = DisconnectReasons::default();
+        reasons.insert(2, DisconnectReason::from_code(DisconnectReason::KICKED));
+        reasons.insert_if_absent(2, DisconnectReason::from_code(DisconnectReason::TIMEOUT));
+        assert_eq!(reasons.get(2).unwrap().code, DisconnectReason::KICKED);
+
+        reasons.remove(2);
+        assert!(reasons.get(2).is_none());
+        assert!(reasons.peer_ids.is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+//! Fragmentation of the unreliable packets larger than a datagram.
+//!
+//! Each datagram ends with a trailer containing the channel of the packet, its
+//! ordering count (0 for unordered packets), the id of the packet, the index of the
+//! fragment and the number of fragments of the packet. Fragments are reassembled
+//! on receive, and the whole packet is dropped if one of its fragments is lost.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+/// The size in bytes of the trailer added at the end of each datagram.
+pub const TRAILER_LEN: usize = 14;
+
+/// The maximum number of fragments of a packet.
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize;
+
+/// The size of a datagram used when the connection doesn't report it.
+pub const DEFAULT_DATAGRAM_SIZE: usize = 1024;
+
+/// The time after which a packet with missing fragments is dropped.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The maximum number of packets being reassembled on a connection.
+const MAX_PENDING_PACKETS: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct Trailer {
+    pub channel: i32,
+    pub count: u32,
+    pub packet_id: u32,
+    pub fragment_index: u8,
+    pub fragment_count: u8,
+}
+
+impl Trailer {
+    pub fn write(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.channel.to_be_bytes());
+        buffer.extend_from_slice(&self.count.to_be_bytes());
+        buffer.extend_from_slice(&self.packet_id.to_be_bytes());
+        buffer.push(self.fragment_index);
+        buffer.push(self.fragment_count);
+    }
+
+    /// Removes the trailer from the end of the datagram and returns it.
+    pub fn read(datagram: &mut Bytes) -> Option<Self> {
+        if datagram.len() < TRAILER_LEN {
+            return None;
+        }
+        let mut trailer = datagram.split_off(datagram.len() - TRAILER_LEN);
+        let trailer = Self {
+            channel: trailer.get_i32(),
+            count: trailer.get_u32(),
+            packet_id: trailer.get_u32(),
+            fragment_index: trailer.get_u8(),
+            fragment_count: trailer.get_u8(),
+        };
+        (trailer.fragment_index < trailer.fragment_count).then_some(trailer)
+    }
+}
+
+/// Returns the maximum size of the data of a fragment.
+pub fn fragment_size(max_datagram_size: Option<usize>) -> usize {
+    max_datagram_size
+        .unwrap_or(DEFAULT_DATAGRAM_SIZE)
+        .saturating_sub(TRAILER_LEN)
+        .max(1)
+}
+
+/// Returns the maximum size of an unreliable packet.
+pub fn max_packet_size(max_datagram_size: Option<usize>) -> usize {
+    fragment_size(max_datagram_size) * MAX_FRAGMENTS
+}
+
+struct PendingPacket {
+    fragments: Vec<Option<Bytes>>,
+    missing_fragments: usize,
+    started: Instant,
+}
+
+/// Reassembles the fragments received on a connection.
+#[derive(Default)]
+pub struct Reassembler {
+    pending_packets: HashMap<(i32, u32), PendingPacket>,
+}
+
+impl Reassembler {
+    /// Adds a fragment, returning the packet if all of its fragments have been received.
+    pub fn push(&mut self, trailer: Trailer, fragment: Bytes) -> Option<Bytes> {
+        if trailer.fragment_count == 1 {
+            return Some(fragment);
+        }
+
+        // Drop the packets that will likely never be completed
+        let now = Instant::now();
+        let key = (trailer.channel, trailer.packet_id);
+        self.pending_packets
+            .retain(|_, packet| now.duration_since(packet.started) < FRAGMENT_TIMEOUT);
+        if self.pending_packets.len() >= MAX_PENDING_PACKETS
+            && !self.pending_packets.contains_key(&key)
+        {
+            let oldest = self
+                .pending_packets
+                .iter()
+                .min_by_key(|(_, packet)| packet.started)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.pending_packets.remove(&oldest);
+            }
+        }
+
+        let packet = self
+            .pending_packets
+            .entry(key)
+            .or_insert_with(|| PendingPacket {
+                fragments: vec![None; trailer.fragment_count as usize],
+                missing_fragments: trailer.fragment_count as usize,
+                started: now,
+            });
+        let slot = packet.fragments.get_mut(trailer.fragment_index as usize)?;
+        if slot.is_none() {
+            *slot = Some(fragment);
+            packet.missing_fragments -= 1;
+        }
+        if packet.missing_fragments > 0 {
+            return None;
+        }
+
+        let packet = self.pending_packets.remove(&key)?;
+        let mut buffer = BytesMut::new();
+        for fragment in packet.fragments.into_iter().flatten() {
+            buffer.extend_from_slice(&fragment);
+        }
+        Some(buffer.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trailer(packet_id: u32, fragment_index: u8, fragment_count: u8) -> Trailer {
+        Trailer {
+            channel: 3,
+            count: 0,
+            packet_id,
+            fragment_index,
+            fragment_count,
+        }
+    }
+
+    fn fragment(data: &'static [u8]) -> Bytes {
+        Bytes::from_static(data)
+    }
+
+    #[test]
+    fn reads_written_trailers() {
+        let mut datagram = b"data".to_vec();
+        let written = Trailer {
+            channel: -5,
+            count: 42,
+            packet_id: 7,
+            fragment_index: 1,
+            fragment_count: 2,
+        };
+        written.write(&mut datagram);
+        assert_eq!(datagram.len(), 4 + TRAILER_LEN);
+
+        let mut datagram = Bytes::from(datagram);
+        let read = Trailer::read(&mut datagram).unwrap();
+        assert_eq!(datagram, fragment(b"data"));
+        assert_eq!(read.channel, -5);
+        assert_eq!(read.count, 42);
+        assert_eq!(read.packet_id, 7);
+        assert_eq!(read.fragment_index, 1);
+        assert_eq!(read.fragment_count, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_trailers() {
+        let mut datagram = Bytes::from(vec![0u8; TRAILER_LEN - 1]);
+        assert!(Trailer::read(&mut datagram).is_none());
+
+        let mut datagram = Vec::new();
+        trailer(1, 2, 2).write(&mut datagram);
+        assert!(Trailer::read(&mut Bytes::from(datagram)).is_none());
+    }
+
+    #[test]
+    fn returns_empty_packets() {
+        let mut datagram = Vec::new();
+        trailer(1, 0, 1).write(&mut datagram);
+        let mut datagram = Bytes::from(datagram);
+        let trailer = Trailer::read(&mut datagram).unwrap();
+
+        let packet = Reassembler::default().push(trailer, datagram);
+        assert_eq!(packet, Some(Bytes::new()));
+    }
+
+    #[test]
+    fn reassembles_fragments_out_of_order() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(trailer(1, 2, 3), fragment(b"ef")), None);
+        assert_eq!(reassembler.push(trailer(1, 0, 3), fragment(b"ab")), None);
+        assert_eq!(
+            reassembler.push(trailer(1, 1, 3), fragment(b"cd")),
+            Some(fragment(b"abcdef"))
+        );
+        assert!(reassembler.pending_packets.is_empty());
+    }
+
+    #[test]
+    fn ignores_duplicate_fragments() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(trailer(1, 0, 2), fragment(b"ab")), None);
+        assert_eq!(reassembler.push(trailer(1, 0, 2), fragment(b"xx")), None);
+        assert_eq!(
+            reassembler.push(trailer(1, 1, 2), fragment(b"cd")),
+            Some(fragment(b"abcd"))
+        );
+    }
+
+    #[test]
+    fn ignores_fragments_outside_of_the_packet() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(trailer(1, 0, 2), fragment(b"ab")), None);
+        assert_eq!(reassembler.push(trailer(1, 4, 5), fragment(b"xx")), None);
+        assert_eq!(
+            reassembler.push(trailer(1, 1, 2), fragment(b"cd")),
+            Some(fragment(b"abcd"))
+        );
+    }
+
+    #[test]
+    fn drops_incomplete_packets_after_timeout() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(trailer(1, 0, 2), fragment(b"ab")), None);
+        for packet in reassembler.pending_packets.values_mut() {
+            packet.started -= FRAGMENT_TIMEOUT;
+        }
+        assert_eq!(reassembler.push(trailer(1, 1, 2), fragment(b"cd")), None);
+        assert_eq!(reassembler.pending_packets.len(), 1);
+    }
+
+    #[test]
+    fn limits_pending_packets() {
+        let mut reassembler = Reassembler::default();
+        for packet_id in 0..MAX_PENDING_PACKETS as u32 + 1 {
+            assert_eq!(
+                reassembler.push(trailer(packet_id, 0, 2), fragment(b"ab")),
+                None
+            );
+        }
+        assert_eq!(reassembler.pending_packets.len(), MAX_PENDING_PACKETS);
+
+        // The fragments of a pending packet don't evict it, even if it is the oldest one
+        let (&(_, packet_id), oldest) = reassembler.pending_packets.iter_mut().next().unwrap();
+        oldest.started -= Duration::from_millis(10);
+        assert_eq!(
+            reassembler.push(trailer(packet_id, 1, 2), fragment(b"cd")),
+            Some(fragment(b"abcd"))
+        );
+    }
+}
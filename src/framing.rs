@@ -0,0 +1,209 @@
+//! Framing of the packets sent on reliable streams.
+//!
+//! Each packet is prefixed by its length, encoded as a variable-length integer
+//! using 7 bits per byte, the highest bit telling whether more bytes follow.
+//!
+//! Packets are handed to the stream and taken from it without copying them whenever
+//! possible.
+
+use anyhow::bail;
+use bytes::{Buf, Bytes, BytesMut};
+use iroh::endpoint::{RecvStream, SendStream};
+
+use crate::disconnect::DisconnectReason;
+
+/// The maximum number of bytes of an encoded length.
+const MAX_VARINT_LEN: usize = 10;
+
+/// The maximum number of bytes reserved for a packet before its content arrives.
+///
+/// Larger packets grow the buffer as their chunks are received, so that a peer
+/// can't make us allocate memory by announcing packets it never sends.
+const MAX_RESERVED_LEN: usize = 64 * 1024;
+
+/// Writes a packet to the stream.
+pub async fn write_packet(stream: &mut SendStream, packet: Bytes) -> anyhow::Result<()> {
+    let mut prefix = [0u8; MAX_VARINT_LEN];
+    let prefix_len = encode_varint(packet.len() as u64, &mut prefix);
+    let prefix = Bytes::copy_from_slice(&prefix[..prefix_len]);
+    stream.write_all_chunks(&mut [prefix, packet]).await?;
+    Ok(())
+}
+
+/// Reads the packets of a stream from the chunks received by the connection.
+///
+/// Packets contained in a single chunk are returned without copying them. The others
+/// are assembled in a buffer whose memory is reused once the previous packets have
+/// been dropped.
+#[derive(Default)]
+pub struct PacketReader {
+    chunk: Bytes,
+    buffer: BytesMut,
+}
+
+impl PacketReader {
+    /// Reads a packet from the stream.
+    ///
+    /// Fails with a [DisconnectReason] if the packet is larger than the given size,
+    /// in which case the connection should be closed.
+    pub async fn read_packet(
+        &mut self,
+        stream: &mut RecvStream,
+        max_size: usize,
+    ) -> anyhow::Result<Bytes> {
+        let len = self.read_varint(stream).await?;
+        if len > max_size as u64 {
+            return Err(DisconnectReason::new(
+                DisconnectReason::PROTOCOL_ERROR,
+                format!(
+                    "received a reliable packet of {len} bytes, larger than the maximum of {max_size} bytes"
+                ),
+            )
+            .into());
+        }
+        let len = len as usize;
+        if self.chunk.len() >= len {
+            return Ok(self.chunk.split_to(len));
+        }
+        self.buffer.reserve(len.min(MAX_RESERVED_LEN));
+        while self.buffer.len() + self.chunk.len() < len {
+            self.buffer.extend_from_slice(&self.chunk);
+            self.chunk = read_chunk(stream).await?;
+        }
+        let missing = len - self.buffer.len();
+        self.buffer.extend_from_slice(&self.chunk.split_to(missing));
+        Ok(self.buffer.split().freeze())
+    }
+
+    async fn read_varint(&mut self, stream: &mut RecvStream) -> anyhow::Result<u64> {
+        let mut value = 0u64;
+        for index in 0..MAX_VARINT_LEN {
+            if self.chunk.is_empty() {
+                self.chunk = read_chunk(stream).await?;
+            }
+            let byte = self.chunk.get_u8();
+            value |= ((byte & 0x7f) as u64) << (7 * index);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("invalid packet length");
+    }
+}
+
+async fn read_chunk(stream: &mut RecvStream) -> anyhow::Result<Bytes> {
+    match stream.read_chunk(usize::MAX, true).await? {
+        Some(chunk) => Ok(chunk.bytes),
+        None => bail!("the stream has been finished"),
+    }
+}
+
+fn encode_varint(mut value: u64, buffer: &mut [u8; MAX_VARINT_LEN]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[len] = byte;
+            return len + 1;
+        }
+        buffer[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::Endpoint;
+
+    use super::*;
+    use crate::testing;
+
+    /// Opens a stream between two endpoints of the same process.
+    async fn open_stream() -> (Endpoint, Endpoint, SendStream, RecvStream) {
+        let (server, client, connection, remote_connection) = testing::connect().await;
+
+        // The stream is only announced to the receiver once data is written on it
+        let mut send = connection.open_uni().await.unwrap();
+        send.write_all(&[0]).await.unwrap();
+        let mut recv = remote_connection.accept_uni().await.unwrap();
+        let mut byte = [0u8; 1];
+        recv.read_exact(&mut byte).await.unwrap();
+        (server, client, send, recv)
+    }
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut buffer = [0u8; MAX_VARINT_LEN];
+        let len = encode_varint(value, &mut buffer);
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    fn encodes_varints() {
+        assert_eq!(varint(0), [0]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(varint(u64::MAX).len(), MAX_VARINT_LEN);
+    }
+
+    #[tokio::test]
+    async fn reads_written_packets() {
+        let (_server, _client, mut send, mut recv) = open_stream().await;
+        let packets = [
+            Bytes::new(),
+            Bytes::from_static(b"hello"),
+            Bytes::from(vec![7u8; 200 * 1024]),
+            Bytes::from_static(b"world"),
+        ];
+        for packet in &packets {
+            write_packet(&mut send, packet.clone()).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        let mut reader = PacketReader::default();
+        for packet in &packets {
+            assert_eq!(
+                &reader.read_packet(&mut recv, usize::MAX).await.unwrap(),
+                packet
+            );
+        }
+        assert!(reader.read_packet(&mut recv, usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_packets() {
+        let (_server, _client, mut send, mut recv) = open_stream().await;
+        write_packet(&mut send, Bytes::from_static(b"too large"))
+            .await
+            .unwrap();
+
+        let error = PacketReader::default()
+            .read_packet(&mut recv, 8)
+            .await
+            .unwrap_err();
+        let reason = error.downcast::<DisconnectReason>().unwrap();
+        assert_eq!(reason.code, DisconnectReason::PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_lengths() {
+        let (_server, _client, mut send, mut recv) = open_stream().await;
+        send.write_all(&[0xff; MAX_VARINT_LEN + 1]).await.unwrap();
+
+        let mut reader = PacketReader::default();
+        assert!(reader.read_packet(&mut recv, usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn does_not_reserve_announced_length() {
+        let (_server, _client, mut send, mut recv) = open_stream().await;
+        let len = 16 * 1024 * 1024;
+        send.write_all(&varint(len)).await.unwrap();
+        send.finish().unwrap();
+
+        let mut reader = PacketReader::default();
+        assert!(reader.read_packet(&mut recv, len as usize).await.is_err());
+        assert!(reader.buffer.capacity() <= MAX_RESERVED_LEN);
+    }
+}
@@ -0,0 +1,505 @@
+//! Handshake exchanged when a connection is established.
+//!
+//! The client opens a bidirectional stream and sends a hello message containing
+//! the wire protocol version, the game version, the proof that it knows the password
+//! of the server, an authentication payload and the peer id it had before losing its
+//! connection, if it is reconnecting, and the compression modes it can decompress.
+//! When the application authenticates its peers, the server waits for it to accept or
+//! reject the client. The server then either answers with the peer id of the client
+//! and the compression modes it can decompress, or closes the connection with the
+//! reason of the refusal.
+//!
+//! The password is never sent: the proof is an HMAC of keying material exported from
+//! the TLS session, keyed with the password. Both nodes derive the same keying material
+//! which is unique to the connection, so the proof can't be replayed on other connections.
+
+use anyhow::bail;
+use hmac::{Hmac, Mac};
+use iroh::NodeId;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::compression::CompressionModes;
+use crate::config::ConnectionConfig;
+use crate::disconnect::DisconnectReason;
+
+/// The version of the wire protocol, incremented on each incompatible change.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The label of the keying material used to prove the knowledge of the password.
+const PASSWORD_LABEL: &[u8] = b"godot-iroh password";
+
+struct Hello {
+    protocol_version: u32,
+    game_version: String,
+    password_proof: Vec<u8>,
+    auth_payload: Vec<u8>,
+    /// The peer id the client wants to resume, or 0 for a new peer.
+    resumed_peer_id: i32,
+    compression_modes: CompressionModes,
+}
+
+/// A client waiting for the application to accept or reject it.
+pub struct AuthRequest {
+    pub node_id: NodeId,
+    pub payload: Vec<u8>,
+    pub response: oneshot::Sender<Result<(), DisconnectReason>>,
+}
+
+impl Hello {
+    async fn write(&self, stream: &mut SendStream) -> anyhow::Result<()> {
+        stream.write_u32(self.protocol_version).await?;
+        write_string(stream, &self.game_version).await?;
+        write_bytes(stream, &self.password_proof).await?;
+        write_bytes(stream, &self.auth_payload).await?;
+        stream.write_i32(self.resumed_peer_id).await?;
+        stream.write_u8(self.compression_modes.bits()).await?;
+        Ok(())
+    }
+
+    async fn read(stream: &mut RecvStream) -> anyhow::Result<Self> {
+        Ok(Self {
+            protocol_version: stream.read_u32().await?,
+            game_version: read_string(stream).await?,
+            password_proof: read_bytes(stream).await?,
+            auth_payload: read_bytes(stream).await?,
+            resumed_peer_id: stream.read_i32().await?,
+            compression_modes: CompressionModes::from_bits(stream.read_u8().await?),
+        })
+    }
+
+    /// Returns the reason why the client must be refused, if any.
+    fn check(
+        &self,
+        connection: &Connection,
+        config: &ConnectionConfig,
+    ) -> Option<DisconnectReason> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Some(DisconnectReason::new(
+                DisconnectReason::VERSION_MISMATCH,
+                format!(
+                    "incompatible protocol version: the server uses version {PROTOCOL_VERSION} but the client uses version {}",
+                    self.protocol_version
+                ),
+            ));
+        }
+        if self.game_version != config.game_version {
+            return Some(DisconnectReason::new(
+                DisconnectReason::VERSION_MISMATCH,
+                format!(
+                    "incompatible game version: the server uses version \"{}\" but the client uses version \"{}\"",
+                    config.game_version, self.game_version
+                ),
+            ));
+        }
+        if !config.password.is_empty() {
+            if self.password_proof.is_empty() {
+                return Some(DisconnectReason::new(
+                    DisconnectReason::WRONG_PASSWORD,
+                    "the server requires a password",
+                ));
+            }
+            let valid = password_mac(connection, &config.password)
+                .is_ok_and(|mac| mac.verify_slice(&self.password_proof).is_ok());
+            if !valid {
+                return Some(DisconnectReason::from_code(
+                    DisconnectReason::WRONG_PASSWORD,
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Returns the HMAC of the keying material of the connection, keyed with the password.
+fn password_mac(connection: &Connection, password: &str) -> anyhow::Result<Hmac<Sha256>> {
+    let mut keying_material = [0u8; 32];
+    if connection
+        .export_keying_material(&mut keying_material, PASSWORD_LABEL, &[])
+        .is_err()
+    {
+        bail!("failed to export keying material from the connection");
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(password.as_bytes())?;
+    mac.update(&keying_material);
+    Ok(mac)
+}
+
+/// Performs the handshake on the client side and returns the peer id given by the server,
+/// along with the compression modes supported by the server.
+///
+/// A reconnecting client gives its previous peer id, which the server gives back if it
+/// still remembers the client.
+pub async fn connect(
+    connection: &Connection,
+    config: &ConnectionConfig,
+    resumed_peer_id: Option<i32>,
+) -> anyhow::Result<(i32, CompressionModes)> {
+    let result = async {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        let password_proof = match config.password.is_empty() {
+            true => Vec::new(),
+            false => password_mac(connection, &config.password)?
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        };
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: config.game_version.clone(),
+            password_proof,
+            auth_payload: config.auth_payload.clone(),
+            resumed_peer_id: resumed_peer_id.unwrap_or(0),
+            compression_modes: CompressionModes::SUPPORTED,
+        };
+        hello.write(&mut send).await?;
+        send.finish()?;
+        let peer_id = recv.read_i32().await?;
+        let compression_modes = CompressionModes::from_bits(recv.read_u8().await?);
+        Ok((peer_id, compression_modes))
+    }
+    .await;
+
+    // Report the reason given by the server if it closed the connection
+    result.map_err(|error: anyhow::Error| match connection.close_reason() {
+        Some(error) => DisconnectReason::from_connection_error(&error).into(),
+        None => error,
+    })
+}
+
+/// Performs the handshake on the server side, giving a peer id to the client and returning it,
+/// along with the compression modes supported by the client.
+///
+/// The client gets back the resumable peer id if it asks for it, otherwise it gets the given
+/// peer id. If an authenticator is given, new clients are only accepted once the application
+/// accepted them.
+pub async fn accept(
+    connection: &Connection,
+    peer_id: i32,
+    resumable_peer_id: Option<i32>,
+    config: &ConnectionConfig,
+    authenticator: Option<&mpsc::Sender<AuthRequest>>,
+) -> anyhow::Result<(i32, CompressionModes)> {
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let hello = match Hello::read(&mut recv).await {
+        Ok(hello) => hello,
+        Err(error) => {
+            let reason = DisconnectReason::new(
+                DisconnectReason::VERSION_MISMATCH,
+                "invalid handshake, the client is probably using an incompatible version",
+            );
+            reason.close(connection);
+            return Err(error.context(reason));
+        }
+    };
+    if let Some(reason) = hello.check(connection, config) {
+        reason.close(connection);
+        return Err(reason.into());
+    }
+    if resumable_peer_id.is_some_and(|resumable_peer_id| resumable_peer_id == hello.resumed_peer_id)
+    {
+        write_welcome(&mut send, hello.resumed_peer_id).await?;
+        return Ok((hello.resumed_peer_id, hello.compression_modes));
+    }
+    if let Some(authenticator) = authenticator {
+        let (response, decision) = oneshot::channel();
+        let request = AuthRequest {
+            node_id: connection.remote_node_id()?,
+            payload: hello.auth_payload,
+            response,
+        };
+        authenticator.send(request).await?;
+        let reason = match decision.await {
+            Ok(Ok(())) => None,
+            Ok(Err(reason)) => Some(reason),
+            Err(_) => Some(DisconnectReason::from_code(
+                DisconnectReason::AUTHENTICATION_FAILED,
+            )),
+        };
+        if let Some(reason) = reason {
+            reason.close(connection);
+            return Err(reason.into());
+        }
+    }
+    write_welcome(&mut send, peer_id).await?;
+    Ok((peer_id, hello.compression_modes))
+}
+
+/// Sends the answer of the server to an accepted client.
+async fn write_welcome(send: &mut SendStream, peer_id: i32) -> anyhow::Result<()> {
+    send.write_i32(peer_id).await?;
+    send.write_u8(CompressionModes::SUPPORTED.bits()).await?;
+    send.finish()?;
+    Ok(())
+}
+
+async fn write_string(stream: &mut SendStream, string: &str) -> anyhow::Result<()> {
+    write_bytes(stream, string.as_bytes()).await
+}
+
+async fn read_string(stream: &mut RecvStream) -> anyhow::Result<String> {
+    Ok(String::from_utf8(read_bytes(stream).await?)?)
+}
+
+async fn write_bytes(stream: &mut SendStream, bytes: &[u8]) -> anyhow::Result<()> {
+    let Ok(len) = u16::try_from(bytes.len()) else {
+        bail!("data too long to be sent during the handshake");
+    };
+    stream.write_u16(len).await?;
+    stream.write_all(bytes).await?;
+    Ok(())
+}
+
+async fn read_bytes(stream: &mut RecvStream) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+    let mut buffer = vec![0u8; len as usize];
+    AsyncReadExt::read_exact(stream, &mut buffer).await?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn config_with_password(password: &str) -> ConnectionConfig {
+        ConnectionConfig {
+            password: password.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Performs the handshake between a client and a server with the given configurations,
+    /// returning the result of each side.
+    async fn handshake(
+        client_config: &ConnectionConfig,
+        server_config: &ConnectionConfig,
+        resumed_peer_id: Option<i32>,
+        resumable_peer_id: Option<i32>,
+    ) -> (
+        anyhow::Result<(i32, CompressionModes)>,
+        anyhow::Result<(i32, CompressionModes)>,
+    ) {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        tokio::join!(
+            connect(&connection, client_config, resumed_peer_id),
+            accept(
+                &remote_connection,
+                2,
+                resumable_peer_id,
+                server_config,
+                None
+            ),
+        )
+    }
+
+    fn disconnect_code(result: anyhow::Result<(i32, CompressionModes)>) -> u32 {
+        result
+            .unwrap_err()
+            .downcast::<DisconnectReason>()
+            .unwrap()
+            .code
+    }
+
+    #[tokio::test]
+    async fn accepts_the_right_password() {
+        let config = config_with_password("secret");
+        let (client, server) = handshake(&config, &config, None, None).await;
+        assert_eq!(client.unwrap().0, 2);
+        assert_eq!(server.unwrap().0, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_password() {
+        let (client, server) = handshake(
+            &config_with_password("guess"),
+            &config_with_password("secret"),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(disconnect_code(client), DisconnectReason::WRONG_PASSWORD);
+        assert_eq!(disconnect_code(server), DisconnectReason::WRONG_PASSWORD);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_missing_password() {
+        let (client, server) = handshake(
+            &ConnectionConfig::default(),
+            &config_with_password("secret"),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(disconnect_code(client), DisconnectReason::WRONG_PASSWORD);
+        assert_eq!(disconnect_code(server), DisconnectReason::WRONG_PASSWORD);
+    }
+
+    #[tokio::test]
+    async fn ignores_a_password_the_server_does_not_expect() {
+        let (client, server) = handshake(
+            &config_with_password("secret"),
+            &ConnectionConfig::default(),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(client.unwrap().0, 2);
+        assert_eq!(server.unwrap().0, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_proof_made_for_another_connection() {
+        let (_server, _client, connection, _) = testing::connect().await;
+        let (_other_server, _other_client, _, other_remote_connection) = testing::connect().await;
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: String::new(),
+            password_proof: password_mac(&connection, "secret")
+                .unwrap()
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            auth_payload: Vec::new(),
+            resumed_peer_id: 0,
+            compression_modes: CompressionModes::SUPPORTED,
+        };
+        let reason = hello
+            .check(&other_remote_connection, &config_with_password("secret"))
+            .unwrap();
+        assert_eq!(reason.code, DisconnectReason::WRONG_PASSWORD);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_truncated_proof() {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        let mut proof = password_mac(&connection, "secret")
+            .unwrap()
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        proof.truncate(16);
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: String::new(),
+            password_proof: proof,
+            auth_payload: Vec::new(),
+            resumed_peer_id: 0,
+            compression_modes: CompressionModes::SUPPORTED,
+        };
+        let reason = hello
+            .check(&remote_connection, &config_with_password("secret"))
+            .unwrap();
+        assert_eq!(reason.code, DisconnectReason::WRONG_PASSWORD);
+    }
+
+    fn hello(protocol_version: u32) -> Hello {
+        Hello {
+            protocol_version,
+            game_version: "1.2".to_string(),
+            password_proof: vec![1, 2, 3],
+            auth_payload: b"token".to_vec(),
+            resumed_peer_id: 7,
+            compression_modes: CompressionModes::from_bits(0b100),
+        }
+    }
+
+    /// Sends the given bytes as the hello of a client and returns the result of the server.
+    async fn accept_raw(bytes: &[u8]) -> anyhow::Result<(i32, CompressionModes)> {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        let (mut send, _recv) = connection.open_bi().await.unwrap();
+        send.write_all(bytes).await.unwrap();
+        send.finish().unwrap();
+        let config = ConnectionConfig::default();
+        accept(&remote_connection, 2, None, &config, None).await
+    }
+
+    #[tokio::test]
+    async fn reads_written_hellos() {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        let (mut send, _) = connection.open_bi().await.unwrap();
+        hello(PROTOCOL_VERSION).write(&mut send).await.unwrap();
+        send.finish().unwrap();
+        let (_, mut recv) = remote_connection.accept_bi().await.unwrap();
+
+        let read = Hello::read(&mut recv).await.unwrap();
+        let written = hello(PROTOCOL_VERSION);
+        assert_eq!(read.protocol_version, written.protocol_version);
+        assert_eq!(read.game_version, written.game_version);
+        assert_eq!(read.password_proof, written.password_proof);
+        assert_eq!(read.auth_payload, written.auth_payload);
+        assert_eq!(read.resumed_peer_id, written.resumed_peer_id);
+        assert_eq!(
+            read.compression_modes.bits(),
+            written.compression_modes.bits()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_another_protocol_version() {
+        let (_server, _client, connection, remote_connection) = testing::connect().await;
+        let (mut send, _recv) = connection.open_bi().await.unwrap();
+        hello(PROTOCOL_VERSION + 1).write(&mut send).await.unwrap();
+        send.finish().unwrap();
+
+        let config = ConnectionConfig {
+            game_version: "1.2".to_string(),
+            ..Default::default()
+        };
+        let server = accept(&remote_connection, 2, None, &config, None).await;
+        assert_eq!(disconnect_code(server), DisconnectReason::VERSION_MISMATCH);
+    }
+
+    #[tokio::test]
+    async fn rejects_another_game_version() {
+        let client_config = ConnectionConfig {
+            game_version: "1.0".to_string(),
+            ..Default::default()
+        };
+        let server_config = ConnectionConfig {
+            game_version: "2.0".to_string(),
+            ..Default::default()
+        };
+        let (client, server) = handshake(&client_config, &server_config, None, None).await;
+        assert_eq!(disconnect_code(client), DisconnectReason::VERSION_MISMATCH);
+        assert_eq!(disconnect_code(server), DisconnectReason::VERSION_MISMATCH);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_hellos() {
+        let server = accept_raw(&PROTOCOL_VERSION.to_be_bytes()).await;
+        assert_eq!(disconnect_code(server), DisconnectReason::VERSION_MISMATCH);
+
+        let mut bytes = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0, 10, b'1']);
+        let server = accept_raw(&bytes).await;
+        assert_eq!(disconnect_code(server), DisconnectReason::VERSION_MISMATCH);
+    }
+
+    #[tokio::test]
+    async fn resumes_the_peer_id_the_server_remembers() {
+        let config = ConnectionConfig::default();
+        let (client, server) = handshake(&config, &config, Some(7), Some(7)).await;
+        assert_eq!(client.unwrap().0, 7);
+        assert_eq!(server.unwrap().0, 7);
+
+        let (client, server) = handshake(&config, &config, Some(7), None).await;
+        assert_eq!(client.unwrap().0, 2);
+        assert_eq!(server.unwrap().0, 2);
+
+        let (client, server) = handshake(&config, &config, None, Some(7)).await;
+        assert_eq!(client.unwrap().0, 2);
+        assert_eq!(server.unwrap().0, 2);
+    }
+
+    #[tokio::test]
+    async fn exchanges_compression_modes() {
+        let config = ConnectionConfig::default();
+        let (client, server) = handshake(&config, &config, None, None).await;
+        let supported = CompressionModes::SUPPORTED.bits();
+        assert_eq!(client.unwrap().1.bits(), supported);
+        assert_eq!(server.unwrap().1.bits(), supported);
+    }
+}
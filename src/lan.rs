@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_lite::StreamExt;
+use godot::classes::{INode, Json, Node};
+use godot::prelude::*;
+use iroh::discovery::mdns::MdnsDiscovery;
+use iroh::discovery::{Discovery, DiscoveryItem};
+use iroh::node_info::UserData;
+use iroh::{NodeId, SecretKey};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio::task::JoinHandle;
+
+use crate::{IrohRuntime, ticket};
+
+/// Prefix of the discovery user data published by servers advertised on the local network.
+const LAN_MARKER: &str = "godot-iroh:";
+
+/// Interval between two checks that the known servers are still reachable.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum time to wait for a known server to be resolved before considering it lost.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Converts the metadata of a server into the user data published on the local network.
+pub fn lan_user_data(metadata: &Dictionary) -> anyhow::Result<UserData> {
+    let metadata = Json::stringify(&metadata.to_variant()).to_string();
+    format!("{LAN_MARKER}{metadata}")
+        .try_into()
+        .with_context(|| {
+            format!(
+                "metadata exceeds the maximum size of {} bytes",
+                UserData::MAX_LENGTH - LAN_MARKER.len()
+            )
+        })
+}
+
+enum LanEvent {
+    Found(String, String),
+    Updated(String, String),
+    Lost(String),
+}
+
+struct LanServer {
+    connection_string: String,
+    metadata: String,
+}
+
+/// Lists the servers advertised on the local network.
+///
+/// Servers are advertised by calling `advertise_on_lan` on an `IrohServer`
+/// that has been started with local network discovery enabled.
+#[derive(GodotClass)]
+#[class(init, base=Node)]
+struct IrohLanBrowser {
+    base: Base<Node>,
+    browsing: Option<(JoinHandle<anyhow::Result<()>>, Receiver<LanEvent>)>,
+}
+
+#[godot_api]
+impl IrohLanBrowser {
+    /// Emitted when a server is found on the local network.
+    #[signal]
+    fn server_found(connection_string: GString, metadata: Dictionary);
+
+    /// Emitted when the metadata or the addresses of a server have changed.
+    #[signal]
+    fn server_updated(connection_string: GString, metadata: Dictionary);
+
+    /// Emitted when a server is no longer reachable on the local network.
+    #[signal]
+    fn server_lost(connection_string: GString);
+
+    /// Starts looking for servers on the local network.
+    #[func]
+    fn start(&mut self) {
+        if self.browsing.is_some() {
+            return;
+        }
+        let (event_sender, event_receiver) = channel(32);
+        let handle = IrohRuntime::spawn(browse(event_sender));
+        self.browsing = Some((handle, event_receiver));
+    }
+
+    /// Stops looking for servers on the local network.
+    #[func]
+    fn stop(&mut self) {
+        if let Some((handle, _)) = self.browsing.take() {
+            handle.abort();
+        }
+    }
+
+    /// Returns `true` if the browser is looking for servers.
+    #[func]
+    fn is_browsing(&self) -> bool {
+        self.browsing.is_some()
+    }
+}
+
+#[godot_api]
+impl INode for IrohLanBrowser {
+    fn process(&mut self, _delta: f64) {
+        let Some((handle, event_receiver)) = &mut self.browsing else {
+            return;
+        };
+        let mut events = Vec::new();
+        loop {
+            match event_receiver.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if let Ok(Err(error)) = IrohRuntime::block_on(handle) {
+                        godot_error!("failed to browse the local network: {error}");
+                    }
+                    self.browsing = None;
+                    break;
+                }
+            }
+        }
+        for event in events {
+            match event {
+                LanEvent::Found(connection_string, metadata) => self.base_mut().emit_signal(
+                    "server_found",
+                    &[
+                        GString::from(connection_string).to_variant(),
+                        parse_metadata(&metadata).to_variant(),
+                    ],
+                ),
+                LanEvent::Updated(connection_string, metadata) => self.base_mut().emit_signal(
+                    "server_updated",
+                    &[
+                        GString::from(connection_string).to_variant(),
+                        parse_metadata(&metadata).to_variant(),
+                    ],
+                ),
+                LanEvent::Lost(connection_string) => self.base_mut().emit_signal(
+                    "server_lost",
+                    &[GString::from(connection_string).to_variant()],
+                ),
+            };
+        }
+    }
+
+    fn exit_tree(&mut self) {
+        self.stop();
+    }
+}
+
+fn parse_metadata(metadata: &str) -> Dictionary {
+    Json::parse_string(metadata)
+        .try_to::<Dictionary>()
+        .unwrap_or_default()
+}
+
+async fn browse(event_sender: Sender<LanEvent>) -> anyhow::Result<()> {
+    let discovery = MdnsDiscovery::new(SecretKey::generate(rand::rngs::OsRng).public())?;
+    let mut items = discovery
+        .subscribe()
+        .context("local network discovery is not available")?;
+    let mut servers = HashMap::new();
+    let mut check_interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        let mut events = Vec::new();
+        tokio::select! {
+            item = items.next() => {
+                let Some(item) = item else {
+                    break;
+                };
+                events.extend(update_server(&mut servers, item.node_id(), Some(item)));
+            }
+            _ = check_interval.tick() => {
+                // Resolving a node that is still announced answers immediately
+                let node_ids = servers.keys().copied().collect::<Vec<_>>();
+                for node_id in node_ids {
+                    let item = match discovery.resolve(node_id) {
+                        Some(mut stream) => tokio::time::timeout(RESOLVE_TIMEOUT, stream.next())
+                            .await
+                            .ok()
+                            .flatten()
+                            .and_then(Result::ok),
+                        None => None,
+                    };
+                    events.extend(update_server(&mut servers, node_id, item));
+                }
+            }
+        }
+        for event in events {
+            if event_sender.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn update_server(
+    servers: &mut HashMap<NodeId, LanServer>,
+    node_id: NodeId,
+    item: Option<DiscoveryItem>,
+) -> Option<LanEvent> {
+    let metadata = item.as_ref().and_then(|item| {
+        let user_data = item.user_data()?;
+        Some(user_data.as_ref().strip_prefix(LAN_MARKER)?.to_string())
+    });
+    let (Some(item), Some(metadata)) = (item, metadata) else {
+        let server = servers.remove(&node_id)?;
+        return Some(LanEvent::Lost(server.connection_string));
+    };
+    let connection_string = ticket::encode(&item.into_node_addr());
+    let server = LanServer {
+        connection_string: connection_string.clone(),
+        metadata: metadata.clone(),
+    };
+    match servers.insert(node_id, server) {
+        None => Some(LanEvent::Found(connection_string, metadata)),
+        Some(previous)
+            if previous.connection_string != connection_string || previous.metadata != metadata =>
+        {
+            Some(LanEvent::Updated(connection_string, metadata))
+        }
+        Some(_) => None,
+    }
+}
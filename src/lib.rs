@@ -6,11 +6,39 @@ use tokio::{
     task::JoinHandle,
 };
 
-const ALPN: &[u8] = b"godot-iroh/0.1";
+/// The protocol name negotiated with other nodes.
+///
+/// Changed along with the handshake, so that nodes using an older handshake fail to
+/// connect right away instead of waiting for each other.
+const ALPN: &[u8] = b"godot-iroh/0.2";
 
 mod client;
+mod compression;
+mod config;
 mod connection;
+mod connection_type;
+mod debugger;
+mod disconnect;
+mod fragmentation;
+mod framing;
+mod handshake;
+mod lan;
+mod monitors;
+mod secret_key;
+mod send_queue;
 mod server;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod ticket;
+
+/// Internals measured by the benchmarks, not part of the API of the extension.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::compression::OutgoingPacket;
+    pub use crate::config::IrohCompressionMode;
+    pub use crate::framing::{PacketReader, write_packet};
+}
 
 struct MyExtension;
 
@@ -19,11 +47,13 @@ unsafe impl ExtensionLibrary for MyExtension {
     fn on_level_init(level: InitLevel) {
         if level == InitLevel::Scene {
             Engine::singleton().register_singleton("IrohRuntime", &IrohRuntime::new_alloc());
+            monitors::register();
         }
     }
 
     fn on_level_deinit(level: InitLevel) {
         if level == InitLevel::Scene {
+            monitors::unregister();
             let mut engine = Engine::singleton();
             let singleton = engine
                 .get_singleton("IrohRuntime")
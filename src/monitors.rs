@@ -0,0 +1,152 @@
+//! Custom monitors registered in the `Performance` singleton.
+//!
+//! The monitors are shared by every server and client of the game, and are shown
+//! in the "Monitors" tab of the editor debugger under the `godot_iroh` category.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use godot::classes::Performance;
+use godot::classes::multiplayer_peer::TransferMode;
+use godot::prelude::*;
+use iroh::endpoint::Connection;
+
+/// The open connections, indexed by their stable id.
+static CONNECTIONS: LazyLock<Mutex<HashMap<usize, Connection>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The bytes sent and received for each transfer mode.
+static BYTES_SENT: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+static BYTES_RECEIVED: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+
+/// The number of received packets waiting to be read by the multiplayer API.
+static QUEUED_PACKETS: AtomicI64 = AtomicI64::new(0);
+
+/// The number of unreliable ordered packets dropped because a newer packet was received.
+static DROPPED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of received datagrams dropped because their trailer is invalid.
+static MALFORMED_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+
+const MODES: [(TransferMode, &str); 3] = [
+    (TransferMode::UNRELIABLE, "unreliable"),
+    (TransferMode::UNRELIABLE_ORDERED, "unreliable_ordered"),
+    (TransferMode::RELIABLE, "reliable"),
+];
+
+pub fn add_connection(connection: &Connection) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.insert(connection.stable_id(), connection.clone());
+}
+
+pub fn remove_connection(connection: &Connection) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.remove(&connection.stable_id());
+}
+
+pub fn record_sent(mode: TransferMode, bytes: usize) {
+    BYTES_SENT[mode_index(mode)].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn record_received(mode: TransferMode, bytes: usize) {
+    BYTES_RECEIVED[mode_index(mode)].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Adds the given number of packets to the queued packets, negative when packets are read.
+pub fn record_queued(count: i64) {
+    QUEUED_PACKETS.fetch_add(count, Ordering::Relaxed);
+}
+
+pub fn record_dropped() {
+    DROPPED_PACKETS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_malformed() {
+    MALFORMED_DATAGRAMS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn mode_index(mode: TransferMode) -> usize {
+    MODES
+        .iter()
+        .position(|(other_mode, _)| *other_mode == mode)
+        .unwrap_or(0)
+}
+
+/// Registers the monitors in the `Performance` singleton.
+pub fn register() {
+    let mut performance = Performance::singleton();
+    for (id, callable) in monitors() {
+        if !performance.has_custom_monitor(&id) {
+            performance.add_custom_monitor(&id, &callable);
+        }
+    }
+}
+
+/// Removes the monitors from the `Performance` singleton.
+pub fn unregister() {
+    let mut performance = Performance::singleton();
+    for (id, _) in monitors() {
+        if performance.has_custom_monitor(&id) {
+            performance.remove_custom_monitor(&id);
+        }
+    }
+}
+
+fn monitors() -> Vec<(StringName, Callable)> {
+    let mut monitors = vec![
+        monitor("peers", || {
+            (CONNECTIONS.lock().unwrap().len() as i64).to_variant()
+        }),
+        monitor("rtt_ms", || {
+            let connections = CONNECTIONS.lock().unwrap();
+            let total: f64 = connections
+                .values()
+                .map(|connection| connection.rtt().as_secs_f64() * 1000.0)
+                .sum();
+            (total / connections.len().max(1) as f64).to_variant()
+        }),
+        monitor("queued_packets", || {
+            QUEUED_PACKETS.load(Ordering::Relaxed).to_variant()
+        }),
+        monitor("dropped_ordered_packets", || {
+            (DROPPED_PACKETS.load(Ordering::Relaxed) as i64).to_variant()
+        }),
+        monitor("malformed_datagrams", || {
+            (MALFORMED_DATAGRAMS.load(Ordering::Relaxed) as i64).to_variant()
+        }),
+    ];
+    for (index, (_, name)) in MODES.iter().enumerate() {
+        monitors.push(rate_monitor(
+            &format!("{name}_bytes_sent_per_second"),
+            &BYTES_SENT[index],
+        ));
+        monitors.push(rate_monitor(
+            &format!("{name}_bytes_received_per_second"),
+            &BYTES_RECEIVED[index],
+        ));
+    }
+    monitors
+}
+
+fn monitor(name: &str, mut value: impl FnMut() -> Variant + 'static) -> (StringName, Callable) {
+    let id = StringName::from(format!("godot_iroh/{name}"));
+    let callable = Callable::from_local_fn(&id.to_string(), move |_| Ok(value()));
+    (id, callable)
+}
+
+/// Creates a monitor of the number of bytes per second counted by the given counter.
+fn rate_monitor(name: &str, counter: &'static AtomicU64) -> (StringName, Callable) {
+    let mut last_sample = (counter.load(Ordering::Relaxed), Instant::now());
+    monitor(name, move || {
+        let (last_bytes, last_instant) = last_sample;
+        let bytes = counter.load(Ordering::Relaxed);
+        let elapsed = last_instant.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0.to_variant();
+        }
+        last_sample = (bytes, Instant::now());
+        ((bytes - last_bytes) as f64 / elapsed).to_variant()
+    })
+}
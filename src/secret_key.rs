@@ -0,0 +1,65 @@
+use base64::prelude::*;
+use godot::prelude::*;
+use iroh::SecretKey;
+
+/// The secret key identifying a node on the network.
+///
+/// Reusing the same secret key when starting a server keeps its
+/// connection string stable across sessions.
+#[derive(GodotClass)]
+#[class(no_init, base=RefCounted)]
+pub struct IrohSecretKey {
+    base: Base<RefCounted>,
+    pub(crate) key: SecretKey,
+}
+
+#[godot_api]
+impl IrohSecretKey {
+    /// Generates a new random secret key.
+    #[func]
+    fn generate() -> Gd<Self> {
+        Self::from_key(SecretKey::generate(rand::rngs::OsRng))
+    }
+
+    /// Imports a secret key from the 32 bytes returned by [Self::to_bytes].
+    ///
+    /// Returns `null` if the bytes are not a valid secret key.
+    #[func]
+    fn from_bytes(bytes: PackedByteArray) -> Option<Gd<Self>> {
+        let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice()) else {
+            godot_error!("invalid secret key: expected 32 bytes, got {}", bytes.len());
+            return None;
+        };
+        Some(Self::from_key(SecretKey::from_bytes(&bytes)))
+    }
+
+    /// Imports a secret key from the string returned by [Self::to_base64].
+    ///
+    /// Returns `null` if the string is not a valid secret key.
+    #[func]
+    fn from_base64(string: GString) -> Option<Gd<Self>> {
+        let Ok(bytes) = BASE64_URL_SAFE_NO_PAD.decode(string.to_string()) else {
+            godot_error!("invalid secret key: malformed base64 string");
+            return None;
+        };
+        Self::from_bytes(PackedByteArray::from(bytes.as_slice()))
+    }
+
+    /// Exports the secret key as 32 bytes.
+    #[func]
+    fn to_bytes(&self) -> PackedByteArray {
+        PackedByteArray::from(self.key.to_bytes().as_slice())
+    }
+
+    /// Exports the secret key as a base64 string.
+    #[func]
+    fn to_base64(&self) -> GString {
+        GString::from(BASE64_URL_SAFE_NO_PAD.encode(self.key.to_bytes()))
+    }
+}
+
+impl IrohSecretKey {
+    pub(crate) fn from_key(key: SecretKey) -> Gd<Self> {
+        Gd::from_init_fn(|base| Self { base, key })
+    }
+}
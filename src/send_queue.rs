@@ -0,0 +1,145 @@
+//! Bounded queues holding the packets waiting to be written to a connection.
+//!
+//! The size of a queue is counted in bytes, so that a peer that can't keep up
+//! with the packets sent to it doesn't make the game run out of memory.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+struct Inner<T> {
+    packets: VecDeque<(T, usize)>,
+    queued_bytes: usize,
+    closed: bool,
+}
+
+/// A queue of packets with a single consumer, the task writing them to the connection.
+pub struct SendQueue<T> {
+    inner: Mutex<Inner<T>>,
+    notify: Notify,
+}
+
+impl<T> Default for SendQueue<T> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                packets: VecDeque::new(),
+                queued_bytes: 0,
+                closed: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+}
+
+impl<T> SendQueue<T> {
+    /// Adds a packet of the given size to the queue, returning false if it doesn't fit.
+    ///
+    /// A packet is always accepted by an empty queue, even if it is larger than the limit.
+    pub fn push(&self, packet: T, size: usize, limit: usize, drop_oldest: bool) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        while !inner.packets.is_empty() && inner.queued_bytes + size > limit {
+            if !drop_oldest {
+                return false;
+            }
+            if let Some((_, dropped_size)) = inner.packets.pop_front() {
+                inner.queued_bytes -= dropped_size;
+            }
+        }
+        inner.packets.push_back((packet, size));
+        inner.queued_bytes += size;
+        drop(inner);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Waits for the next packet, returning `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some((packet, size)) = inner.packets.pop_front() {
+                    inner.queued_bytes -= size;
+                    return Some(packet);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Returns the number of bytes waiting in the queue.
+    pub fn queued_bytes(&self) -> usize {
+        self.inner.lock().unwrap().queued_bytes
+    }
+
+    /// Stops the consumer once the queued packets have been taken.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_packets_when_full() {
+        let queue = SendQueue::default();
+        assert!(queue.push(1, 6, 10, false));
+        assert!(queue.push(2, 4, 10, false));
+        assert!(!queue.push(3, 1, 10, false));
+        assert_eq!(queue.queued_bytes(), 10);
+    }
+
+    #[test]
+    fn accepts_large_packets_when_empty() {
+        let queue = SendQueue::default();
+        assert!(queue.push(1, 100, 10, false));
+        assert!(!queue.push(2, 1, 10, false));
+        assert_eq!(queue.queued_bytes(), 100);
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_packets() {
+        let queue = SendQueue::default();
+        assert!(queue.push(1, 4, 10, true));
+        assert!(queue.push(2, 4, 10, true));
+        assert!(queue.push(3, 4, 10, true));
+        assert_eq!(queue.queued_bytes(), 8);
+
+        // A packet larger than the limit replaces every queued packet
+        assert!(queue.push(4, 20, 10, true));
+        assert_eq!(queue.queued_bytes(), 20);
+        assert_eq!(queue.pop().await, Some(4));
+        assert_eq!(queue.queued_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn pops_packets_in_order_until_closed() {
+        let queue = SendQueue::default();
+        assert!(queue.push(1, 1, 10, false));
+        assert!(queue.push(2, 1, 10, false));
+        queue.close();
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn wakes_up_the_consumer() {
+        let queue = std::sync::Arc::new(SendQueue::default());
+        let consumer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(queue.push(1, 1, 10, false));
+        assert_eq!(consumer.await.unwrap(), Some(1));
+    }
+}
@@ -1,27 +1,77 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::mem::replace;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use godot::classes::file_access::ModeFlags;
 use godot::classes::multiplayer_peer::{ConnectionStatus, TransferMode};
 use godot::classes::{IMultiplayerPeerExtension, MultiplayerPeerExtension};
 use godot::global::Error;
 use godot::prelude::*;
+use godot::tools::GFile;
+use iroh::node_info::UserData;
+use iroh::{NodeId, SecretKey};
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use crate::IrohRuntime;
-use crate::connection::{IrohConnection, IrohListener};
+use crate::compression::OutgoingPacket;
+use crate::config::{IrohCompressionMode, IrohConfig};
+use crate::connection::{self, IrohConnection, IrohListener};
+use crate::connection_type::{IrohConnectionType, connection_type_code, remote_address};
+use crate::debugger::DebuggerReporter;
+use crate::disconnect::{DisconnectReason, DisconnectReasons};
+use crate::handshake::AuthRequest;
+use crate::lan::lan_user_data;
+use crate::monitors;
+use crate::secret_key::IrohSecretKey;
+use crate::{IrohRuntime, ticket};
+
+enum ServerStatus {
+    Starting(JoinHandle<anyhow::Result<IrohListener>>),
+    Listening(Box<IrohListener>),
+    Failed(anyhow::Error),
+    Closed,
+}
 
 #[derive(GodotClass)]
 #[class(tool, no_init, base=MultiplayerPeerExtension)]
 struct IrohServer {
     base: Base<MultiplayerPeerExtension>,
-    listener: IrohListener,
-    accepted_peer_sender: Sender<(i32, IrohConnection)>,
-    accepted_peer_receiver: Receiver<(i32, IrohConnection)>,
+    status: ServerStatus,
+    lan_discovery: bool,
+    lan_user_data: Option<UserData>,
+    accepted_peer_sender: Sender<(i32, anyhow::Result<(i32, IrohConnection)>)>,
+    accepted_peer_receiver: Receiver<(i32, anyhow::Result<(i32, IrohConnection)>)>,
     refuse_new_connections: bool,
+    /// The maximum number of connected peers, or 0 for no limit.
+    ///
+    /// New clients are refused when the limit is reached.
+    #[var]
+    max_peers: i32,
+    /// If enabled, the `peer_authenticating` signal is emitted for each new client,
+    /// and the client only becomes a peer once accepted with [Self::accept_peer].
+    #[var]
+    authenticate_peers: bool,
+    auth_request_sender: Sender<AuthRequest>,
+    auth_request_receiver: Receiver<AuthRequest>,
+    authenticating_peers: HashMap<NodeId, oneshot::Sender<Result<(), DisconnectReason>>>,
+    banned_nodes: HashSet<NodeId>,
+    allowlist: Option<HashSet<NodeId>>,
+    pending_peers: HashSet<i32>,
+    max_packet_size: i32,
+    reconnect_grace_period: Option<Duration>,
+    reconnecting_peers: HashMap<i32, (NodeId, Instant)>,
+    channel_priorities: HashMap<i32, i32>,
+    compression: IrohCompressionMode,
+    channel_compression: HashMap<i32, IrohCompressionMode>,
     peers: HashMap<i32, IrohConnection>,
+    disconnect_reasons: DisconnectReasons,
     last_peer_id: i32,
     received_packets: VecDeque<(i32, i32, TransferMode, Bytes)>,
+    debugger_reporter: DebuggerReporter,
     target_peer_id: i32,
     transfer_channel: i32,
     transfer_mode: TransferMode,
@@ -29,41 +79,169 @@ struct IrohServer {
 
 #[godot_api]
 impl IrohServer {
+    /// Emitted when the server has started listening for incoming connections.
+    #[signal]
+    fn started();
+
+    /// Emitted when the server failed to start.
+    ///
+    /// The error message is returned by the [Self::start_error] function.
+    #[signal]
+    fn start_failed();
+
+    /// Emitted when a client is waiting to be accepted, if `authenticate_peers` is enabled.
+    ///
+    /// The payload is the data sent by the client in its configuration. The client must be
+    /// accepted with [Self::accept_peer] or rejected with [Self::reject_peer] before its
+    /// connection times out. The `node_id` is the connection string identifying the client,
+    /// as returned by [Self::peer_connection_string] once it is connected.
+    #[signal]
+    fn peer_authenticating(node_id: GString, payload: PackedByteArray);
+
+    /// Emitted instead of `peer_disconnected` when a peer lost its connection, if the
+    /// `reconnect_grace_period` of the server configuration is not 0.
+    ///
+    /// The peer keeps its peer id if it reconnects before the end of the grace period,
+    /// in which case the `peer_reconnected` signal is emitted. Otherwise the
+    /// `peer_disconnected` signal is emitted at the end of the grace period.
+    #[signal]
+    fn peer_reconnecting(peer_id: i32);
+
+    /// Emitted when a peer that lost its connection has reconnected with the same peer id.
+    ///
+    /// It is emitted without a prior `peer_reconnecting` signal if the peer reconnected
+    /// before the server noticed that its previous connection was lost.
+    #[signal]
+    fn peer_reconnected(peer_id: i32);
+
+    /// Emitted when the paths used to reach a peer are known or have changed,
+    /// for example when a direct connection replaces a relayed one.
+    ///
+    /// The connection types are listed in `IrohConnectionType`.
+    #[signal]
+    fn connection_type_changed(peer_id: i32, connection_type: i32);
+
     /// Starts a server that is listening for incoming connections.
     ///
     /// Other clients can connect to this server by calling the connect function on `IrohClient`
     /// using the connection string returned by the [Self::connection_string] function.
+    ///
+    /// The server starts in the background: its connection status is `CONNECTING`
+    /// until the `started` signal is emitted. If the server fails to start, the
+    /// `start_failed` signal is emitted and the error message is returned by the
+    /// [Self::start_error] function.
     #[func]
     fn start() -> Gd<Self> {
-        let listener = match IrohRuntime::block_on(IrohListener::new()) {
-            Ok(listener) => listener,
-            Err(error) => panic!("failed to start listening: {error}"),
-        };
-        let (accepted_peer_sender, accepted_peer_receiver) = channel(32);
-        Gd::from_init_fn(|base| Self {
-            base,
-            listener,
-            accepted_peer_sender,
-            accepted_peer_receiver,
-            refuse_new_connections: false,
-            peers: HashMap::new(),
-            last_peer_id: 1,
-            received_packets: VecDeque::new(),
-            transfer_channel: 0,
-            transfer_mode: TransferMode::RELIABLE,
-            target_peer_id: 0,
-        })
+        Self::start_listening(&IrohConfig::new_gd().bind(), None)
+    }
+
+    /// Starts a server that is listening for incoming connections using the given configuration.
+    ///
+    /// If the configuration is invalid, as reported by `IrohConfig.validate`, the server
+    /// fails to start and the `start_failed` signal is emitted.
+    #[func]
+    fn start_with_config(config: Gd<IrohConfig>) -> Gd<Self> {
+        Self::start_listening(&config.bind(), None)
+    }
+
+    /// Starts a server that is listening for incoming connections using the given secret key.
+    ///
+    /// The connection string of the server only depends on its secret key, so reusing the
+    /// same key keeps the connection string stable across sessions.
+    #[func]
+    fn start_with_secret_key(secret_key: Gd<IrohSecretKey>) -> Gd<Self> {
+        let secret_key = secret_key.bind().key.clone();
+        Self::start_listening(&IrohConfig::new_gd().bind(), Some(secret_key))
     }
 
     /// Returns the connection string that can be used to connect to this server.
+    ///
+    /// The connection string contains the relay url and the direct addresses of the
+    /// server known at the time of the call, so clients can reach it without discovery.
+    /// Those addresses are found shortly after the server starts.
     #[func]
     fn connection_string(&self) -> GString {
-        GString::from(self.listener.connection_string())
+        match self.listener() {
+            Some(listener) => GString::from(listener.connection_string()),
+            None => GString::new(),
+        }
+    }
+
+    /// Returns the error message that occurred when starting the server.
+    ///
+    /// This function should be called after receiving the `start_failed` signal.
+    #[func]
+    fn start_error(&self) -> GString {
+        if let ServerStatus::Failed(error) = &self.status {
+            return error.to_string().into();
+        }
+        GString::new()
+    }
+
+    /// Returns the secret key used by this server.
+    ///
+    /// It can be saved and given back to [Self::start_with_secret_key] to
+    /// keep the same connection string the next time the server is started.
+    ///
+    /// Returns `null` if the server has not started yet.
+    #[func]
+    fn secret_key(&self) -> Option<Gd<IrohSecretKey>> {
+        let listener = self.listener()?;
+        Some(IrohSecretKey::from_key(
+            listener.endpoint.secret_key().clone(),
+        ))
+    }
+
+    /// Advertises this server on the local network with the given metadata.
+    ///
+    /// The server and its metadata will be listed by the `IrohLanBrowser` nodes of the
+    /// local network. The server must have been started with `discovery_local_network`
+    /// enabled in its configuration, otherwise `ERR_UNCONFIGURED` is returned. Calling this
+    /// function again updates the metadata.
+    ///
+    /// The metadata is published by every discovery service of the server: if
+    /// `discovery_n0` is also enabled, it is published on the n0 DNS servers as well.
+    #[func]
+    fn advertise_on_lan(&mut self, metadata: Dictionary) -> Error {
+        if !self.lan_discovery {
+            godot_error!("cannot advertise a server started without local network discovery");
+            return Error::ERR_UNCONFIGURED;
+        }
+        match lan_user_data(&metadata) {
+            Ok(user_data) => {
+                self.lan_user_data = Some(user_data);
+                if let Some(listener) = self.listener() {
+                    listener
+                        .endpoint
+                        .set_user_data_for_discovery(self.lan_user_data.clone());
+                }
+                Error::OK
+            }
+            Err(error) => {
+                godot_error!("failed to advertise the server: {error}");
+                Error::ERR_INVALID_PARAMETER
+            }
+        }
+    }
+
+    /// Stops advertising this server on the local network.
+    #[func]
+    fn stop_advertising_on_lan(&mut self) {
+        self.lan_user_data = None;
+        if let Some(listener) = self.listener() {
+            listener.endpoint.set_user_data_for_discovery(None);
+        }
     }
 
     /// Connect to an other server using the connection string.
     #[func]
     fn connect(&mut self, connection_string: GString) {
+        let Some(listener) = self.listener() else {
+            godot_error!("cannot connect to an other server before the server has started");
+            return;
+        };
+        let endpoint = listener.endpoint.clone();
+        let config = listener.config.clone();
         let node_id = connection_string.to_string();
         let peer_id = {
             self.last_peer_id = (self.last_peer_id + 1) % i32::MAX;
@@ -72,13 +250,164 @@ impl IrohServer {
             }
             self.last_peer_id
         };
-        let endpoint = self.listener.endpoint.clone();
         let accepted_peer_sender = self.accepted_peer_sender.clone();
+        self.pending_peers.insert(peer_id);
         IrohRuntime::spawn(async move {
-            let (_, connection) = IrohConnection::connect(endpoint, node_id).await?;
-            accepted_peer_sender.send((peer_id, connection)).await?;
-            Ok::<(), anyhow::Error>(())
+            let connection = IrohConnection::connect(endpoint, &config, node_id, None)
+                .await
+                .map(|(_, connection)| (peer_id, connection));
+            let _ = accepted_peer_sender.send((peer_id, connection)).await;
+        });
+    }
+
+    /// Accepts a client waiting for authentication.
+    ///
+    /// The client becomes a peer and the `peer_connected` signal is emitted shortly after.
+    #[func]
+    fn accept_peer(&mut self, node_id: GString) {
+        if let Some(response) = self.take_authenticating_peer(&node_id) {
+            let _ = response.send(Ok(()));
+        }
+    }
+
+    /// Rejects a client waiting for authentication, sending it the given reason.
+    ///
+    /// The connection of the client fails with the
+    /// `IrohDisconnectReason.AUTHENTICATION_FAILED` code.
+    #[func]
+    fn reject_peer(&mut self, node_id: GString, reason: GString) {
+        if let Some(response) = self.take_authenticating_peer(&node_id) {
+            let reason =
+                DisconnectReason::new(DisconnectReason::AUTHENTICATION_FAILED, reason.to_string());
+            let _ = response.send(Err(reason));
+        }
+    }
+
+    /// Bans the node identified by the given connection string.
+    ///
+    /// The node is disconnected if it is connected, and its future connections are refused
+    /// with the `IrohDisconnectReason.BANNED` code. The node stays banned until
+    /// [Self::unban_node] is called, use [Self::save_ban_list] to keep it across sessions.
+    #[func]
+    fn ban_node(&mut self, connection_string: GString) -> Error {
+        let Some(node_id) = parse_node_id(&connection_string) else {
+            return Error::ERR_INVALID_PARAMETER;
+        };
+        self.banned_nodes.insert(node_id);
+        let reason = DisconnectReason::from_code(DisconnectReason::BANNED);
+        if let Some(response) = self.authenticating_peers.remove(&node_id) {
+            let _ = response.send(Err(reason.clone()));
+        }
+        let banned_peers: Vec<i32> = self
+            .peers
+            .iter()
+            .filter(|(_, connection)| connection.node_id() == node_id)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in banned_peers {
+            self.disconnect_peer_with(peer_id, reason.clone(), false);
+        }
+        Error::OK
+    }
+
+    /// Removes the node identified by the given connection string from the ban list.
+    #[func]
+    fn unban_node(&mut self, connection_string: GString) -> Error {
+        let Some(node_id) = parse_node_id(&connection_string) else {
+            return Error::ERR_INVALID_PARAMETER;
+        };
+        self.banned_nodes.remove(&node_id);
+        Error::OK
+    }
+
+    /// Returns whether the node identified by the given connection string is banned.
+    #[func]
+    fn is_node_banned(&self, connection_string: GString) -> bool {
+        parse_node_id(&connection_string)
+            .is_some_and(|node_id| self.banned_nodes.contains(&node_id))
+    }
+
+    /// Returns the connection strings of the banned nodes.
+    #[func]
+    fn banned_nodes(&self) -> PackedStringArray {
+        encode_node_ids(&self.banned_nodes)
+    }
+
+    /// Saves the ban list to the given file, one connection string per line.
+    #[func]
+    fn save_ban_list(&self, path: GString) -> Error {
+        let result = GFile::open(&path, ModeFlags::WRITE).and_then(|mut file| {
+            for node_id in &self.banned_nodes {
+                writeln!(file, "{}", ticket::encode_node_id(node_id))?;
+            }
+            file.flush()
         });
+        match result {
+            Ok(()) => Error::OK,
+            Err(error) => {
+                godot_error!("failed to save the ban list to {path}: {error}");
+                Error::ERR_FILE_CANT_WRITE
+            }
+        }
+    }
+
+    /// Loads a ban list saved with [Self::save_ban_list], replacing the current ban list.
+    ///
+    /// Connected peers are not disconnected, even if they are part of the new ban list.
+    #[func]
+    fn load_ban_list(&mut self, path: GString) -> Error {
+        let lines = GFile::open(&path, ModeFlags::READ)
+            .and_then(|file| file.lines().collect::<std::io::Result<Vec<String>>>());
+        let lines = match lines {
+            Ok(lines) => lines,
+            Err(error) => {
+                godot_error!("failed to load the ban list from {path}: {error}");
+                return Error::ERR_FILE_CANT_READ;
+            }
+        };
+        let mut banned_nodes = HashSet::new();
+        for line in lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+        {
+            match ticket::decode(line) {
+                Ok(node_addr) => banned_nodes.insert(node_addr.node_id),
+                Err(error) => {
+                    godot_error!("invalid node id {line} in the ban list: {error}");
+                    return Error::ERR_PARSE_ERROR;
+                }
+            };
+        }
+        self.banned_nodes = banned_nodes;
+        Error::OK
+    }
+
+    /// Only allows the nodes identified by the given connection strings to connect.
+    ///
+    /// Other nodes are refused, but connected peers are not disconnected.
+    /// An empty array disables the allowlist, allowing every node that is not banned.
+    #[func]
+    fn set_allowlist(&mut self, connection_strings: PackedStringArray) -> Error {
+        let mut allowlist = HashSet::new();
+        for connection_string in connection_strings.as_slice() {
+            let Some(node_id) = parse_node_id(connection_string) else {
+                return Error::ERR_INVALID_PARAMETER;
+            };
+            allowlist.insert(node_id);
+        }
+        self.allowlist = (!allowlist.is_empty()).then_some(allowlist);
+        Error::OK
+    }
+
+    /// Returns the connection strings of the nodes allowed to connect,
+    /// or an empty array if the allowlist is disabled.
+    #[func]
+    fn allowlist(&self) -> PackedStringArray {
+        match &self.allowlist {
+            Some(allowlist) => encode_node_ids(allowlist),
+            None => PackedStringArray::new(),
+        }
     }
 
     /// Returns a dictionary of all peers currently connected to the server.
@@ -99,15 +428,335 @@ impl IrohServer {
         self.peers
             .get(&peer_id)
             .map(|connection| GString::from(connection.connection_string()))
-            .unwrap_or_else(GString::new)
+            .unwrap_or_default()
+    }
+
+    /// Returns the paths used to reach the given peer, as listed in `IrohConnectionType`.
+    #[func]
+    fn peer_connection_type(&self, peer_id: i32) -> i32 {
+        self.peers
+            .get(&peer_id)
+            .map(|connection| connection_type_code(connection.connection_type()))
+            .unwrap_or(IrohConnectionType::NONE)
+    }
+
+    /// Returns the address used to reach the given peer: its UDP address
+    /// for direct connections, or the url of its relay server.
+    #[func]
+    fn peer_remote_address(&self, peer_id: i32) -> GString {
+        self.peers
+            .get(&peer_id)
+            .map(|connection| GString::from(remote_address(connection.connection_type())))
+            .unwrap_or_default()
+    }
+
+    /// Returns the statistics of the connection to the given peer.
+    ///
+    /// The keys of the dictionary are listed in the README. Returns an empty
+    /// dictionary if the peer is not connected.
+    #[func]
+    fn get_peer_stats(&self, peer_id: i32) -> Dictionary {
+        self.peers
+            .get(&peer_id)
+            .map(|connection| connection.stats())
+            .unwrap_or_default()
+    }
+
+    /// Sets the priority of a reliable channel for all peers, 0 by default.
+    ///
+    /// When the connection is congested, packets of channels with a higher priority
+    /// are sent first, so that gameplay packets are not delayed by large transfers.
+    #[func]
+    fn set_channel_priority(&mut self, channel: i32, priority: i32) {
+        self.channel_priorities.insert(channel, priority);
+        for connection in self.peers.values_mut() {
+            connection.set_channel_priority(channel, priority);
+        }
+    }
+
+    /// Returns the priority of a reliable channel.
+    #[func]
+    fn channel_priority(&self, channel: i32) -> i32 {
+        self.channel_priorities
+            .get(&channel)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the compression of the packets sent to all peers on a channel,
+    /// overriding the `compression` of the configuration.
+    ///
+    /// Packets are sent uncompressed to peers that don't support the compression mode.
+    #[func]
+    fn set_channel_compression(&mut self, channel: i32, compression: IrohCompressionMode) {
+        self.channel_compression.insert(channel, compression);
+        for connection in self.peers.values_mut() {
+            connection.set_channel_compression(channel, compression);
+        }
+    }
+
+    /// Returns the compression of the packets sent on a channel.
+    #[func]
+    fn channel_compression(&self, channel: i32) -> IrohCompressionMode {
+        self.channel_compression
+            .get(&channel)
+            .copied()
+            .unwrap_or(self.compression)
+    }
+
+    /// Sends a packet to each of the given peers, using the current transfer channel and mode.
+    ///
+    /// The packet is compressed once and its buffer is shared by all the peers, which is
+    /// cheaper than sending it to each peer separately. Peers that are not connected are ignored.
+    #[func]
+    fn put_packet_to(&mut self, peer_ids: PackedInt32Array, packet: PackedByteArray) -> Error {
+        self.send_to_peers(peer_ids.as_slice(), packet.as_slice())
+    }
+
+    /// Returns the number of bytes waiting to be sent to the given peer.
+    #[func]
+    fn peer_queued_bytes(&self, peer_id: i32) -> i64 {
+        self.peers
+            .get(&peer_id)
+            .map(|connection| connection.queued_bytes() as i64)
+            .unwrap_or_default()
+    }
+
+    /// Disconnects the given peer, sending it the given code and reason.
+    ///
+    /// The code and the reason can be retrieved by the client using the
+    /// `disconnect_code` and `disconnect_reason` functions of `IrohClient`.
+    /// Codes are listed in `IrohDisconnectReason`.
+    #[func]
+    fn disconnect_peer_with_reason(&mut self, peer_id: i32, code: i32, reason: GString) {
+        let Ok(code) = u32::try_from(code) else {
+            godot_error!("invalid disconnect code {code}");
+            return;
+        };
+        let reason = DisconnectReason::new(code, reason.to_string());
+        self.disconnect_peer_with(peer_id, reason, false);
+    }
+
+    /// Returns the code describing why the given peer has been disconnected.
+    ///
+    /// Returns -1 if the peer is connected or unknown. Only the reasons of the last
+    /// 1024 departed peers are kept.
+    #[func]
+    fn peer_disconnect_code(&self, peer_id: i32) -> i32 {
+        self.disconnect_reasons
+            .get(peer_id)
+            .map(|reason| reason.code as i32)
+            .unwrap_or(-1)
+    }
+
+    /// Returns the message describing why the given peer has been disconnected.
+    #[func]
+    fn peer_disconnect_reason(&self, peer_id: i32) -> GString {
+        self.disconnect_reasons
+            .get(peer_id)
+            .map(|reason| GString::from(reason.to_string()))
+            .unwrap_or_default()
     }
 }
 
+impl IrohServer {
+    fn start_listening(config: &IrohConfig, secret_key: Option<SecretKey>) -> Gd<Self> {
+        let endpoint_config = config.endpoint_config();
+        let max_peers = config.max_peers;
+        let max_packet_size = config.max_packet_size;
+        let compression = config.compression;
+        let reconnect_grace_period = (config.reconnect_grace_period > 0.0)
+            .then(|| Duration::from_secs_f64(config.reconnect_grace_period));
+        // An invalid configuration fails the server when it is first polled
+        let handle = IrohRuntime::spawn(async move {
+            let mut endpoint_config = endpoint_config?;
+            if let Some(secret_key) = secret_key {
+                endpoint_config.builder = endpoint_config.builder.secret_key(secret_key);
+            }
+            IrohListener::new(endpoint_config).await
+        });
+        let (accepted_peer_sender, accepted_peer_receiver) = channel(32);
+        let (auth_request_sender, auth_request_receiver) = channel(32);
+        Gd::from_init_fn(|base| Self {
+            base,
+            status: ServerStatus::Starting(handle),
+            lan_discovery: config.discovery_local_network,
+            lan_user_data: None,
+            accepted_peer_sender,
+            accepted_peer_receiver,
+            refuse_new_connections: false,
+            max_peers,
+            authenticate_peers: false,
+            auth_request_sender,
+            auth_request_receiver,
+            authenticating_peers: HashMap::new(),
+            banned_nodes: HashSet::new(),
+            allowlist: None,
+            pending_peers: HashSet::new(),
+            max_packet_size,
+            reconnect_grace_period,
+            reconnecting_peers: HashMap::new(),
+            channel_priorities: HashMap::new(),
+            compression,
+            channel_compression: HashMap::new(),
+            peers: HashMap::new(),
+            disconnect_reasons: DisconnectReasons::default(),
+            last_peer_id: 1,
+            received_packets: VecDeque::new(),
+            debugger_reporter: DebuggerReporter::default(),
+            transfer_channel: 0,
+            transfer_mode: TransferMode::RELIABLE,
+            target_peer_id: 0,
+        })
+    }
+
+    /// Sends a packet to the given peers with the current transfer channel and mode,
+    /// sharing the same buffer between all of them.
+    fn send_to_peers(&mut self, peer_ids: &[i32], packet: &[u8]) -> Error {
+        let mut packet = OutgoingPacket::new(packet);
+        let mut result = Error::OK;
+        for peer_id in peer_ids {
+            let Some(connection) = self.peers.get_mut(peer_id) else {
+                continue;
+            };
+            let error =
+                connection.send_packet(self.transfer_channel, self.transfer_mode, &mut packet);
+            if error != Error::OK {
+                result = error;
+            }
+        }
+        result
+    }
+
+    fn disconnect_peer_with(&mut self, peer_id: i32, reason: DisconnectReason, force: bool) {
+        if let Some(connection) = self.peers.remove(&peer_id) {
+            connection.close_with_reason(&reason);
+        } else if self.reconnecting_peers.remove(&peer_id).is_none() {
+            return;
+        }
+        self.disconnect_reasons.insert(peer_id, reason);
+        if !force {
+            self.base_mut()
+                .emit_signal("peer_disconnected", &[peer_id.to_variant()]);
+        }
+    }
+
+    fn take_authenticating_peer(
+        &mut self,
+        node_id: &GString,
+    ) -> Option<oneshot::Sender<Result<(), DisconnectReason>>> {
+        let node_id = parse_node_id(node_id)?;
+        self.authenticating_peers.remove(&node_id)
+    }
+
+    fn listener(&self) -> Option<&IrohListener> {
+        match &self.status {
+            ServerStatus::Listening(listener) => Some(listener),
+            _ => None,
+        }
+    }
+}
+
+fn parse_node_id(connection_string: &GString) -> Option<NodeId> {
+    match ticket::decode(&connection_string.to_string()) {
+        Ok(node_addr) => Some(node_addr.node_id),
+        Err(error) => {
+            godot_error!("invalid node id {connection_string}: {error}");
+            None
+        }
+    }
+}
+
+fn encode_node_ids<'a>(node_ids: impl IntoIterator<Item = &'a NodeId>) -> PackedStringArray {
+    node_ids
+        .into_iter()
+        .map(|node_id| GString::from(ticket::encode_node_id(node_id)))
+        .collect()
+}
+
 #[godot_api]
 impl IMultiplayerPeerExtension for IrohServer {
     fn poll(&mut self) {
+        // Wait for the server to start
+        if let ServerStatus::Starting(handle) = &self.status {
+            if !handle.is_finished() {
+                return;
+            }
+            let ServerStatus::Starting(handle) = replace(&mut self.status, ServerStatus::Closed)
+            else {
+                unreachable!()
+            };
+            match IrohRuntime::block_on(handle) {
+                Ok(Ok(listener)) => {
+                    if self.lan_user_data.is_some() {
+                        listener
+                            .endpoint
+                            .set_user_data_for_discovery(self.lan_user_data.clone());
+                    }
+                    self.status = ServerStatus::Listening(Box::new(listener));
+                    self.base_mut().emit_signal("started", &[]);
+                }
+                Ok(Err(error)) => {
+                    self.status = ServerStatus::Failed(error);
+                    self.base_mut().emit_signal("start_failed", &[]);
+                    return;
+                }
+                Err(error) => {
+                    self.status = ServerStatus::Failed(error.into());
+                    self.base_mut().emit_signal("start_failed", &[]);
+                    return;
+                }
+            }
+        }
+        let ServerStatus::Listening(listener) = &mut self.status else {
+            return;
+        };
+
         // Accept new connections
-        while let Ok(connection) = self.listener.receive_connection() {
+        while let Ok(connection) = listener.receive_connection() {
+            let Ok(node_id) = connection.remote_node_id() else {
+                continue;
+            };
+            if self.banned_nodes.contains(&node_id) {
+                DisconnectReason::from_code(DisconnectReason::BANNED).close(&connection);
+                continue;
+            }
+            if let Some(allowlist) = &self.allowlist
+                && !allowlist.contains(&node_id)
+            {
+                DisconnectReason::new(
+                    DisconnectReason::REFUSED,
+                    "you are not allowed to join this server",
+                )
+                .close(&connection);
+                continue;
+            }
+            // The client may reconnect before the server noticed its connection was lost,
+            // in which case the previous connection is replaced once the handshake succeeds
+            let resumable_peer_id = self
+                .reconnecting_peers
+                .iter()
+                .find(|(_, (reconnecting_node_id, _))| *reconnecting_node_id == node_id)
+                .map(|(peer_id, _)| *peer_id)
+                .or_else(|| {
+                    self.peers
+                        .iter()
+                        .find(|(_, connection)| connection.node_id() == node_id)
+                        .map(|(peer_id, _)| *peer_id)
+                });
+            if self.refuse_new_connections && resumable_peer_id.is_none() {
+                DisconnectReason::from_code(DisconnectReason::REFUSED).close(&connection);
+                continue;
+            }
+            let peer_count =
+                self.peers.len() + self.pending_peers.len() + self.reconnecting_peers.len();
+            if self.max_peers > 0
+                && peer_count >= self.max_peers as usize
+                && resumable_peer_id.is_none()
+            {
+                DisconnectReason::from_code(DisconnectReason::SERVER_FULL).close(&connection);
+                continue;
+            }
             let peer_id = {
                 self.last_peer_id = (self.last_peer_id + 1) % i32::MAX;
                 if self.last_peer_id < 2 {
@@ -115,29 +764,98 @@ impl IMultiplayerPeerExtension for IrohServer {
                 }
                 self.last_peer_id
             };
+            self.pending_peers.insert(peer_id);
+            let endpoint = listener.endpoint.clone();
+            let config = listener.config.clone();
+            let authenticator = self
+                .authenticate_peers
+                .then(|| self.auth_request_sender.clone());
             let accepted_peer_sender = self.accepted_peer_sender.clone();
             IrohRuntime::spawn(async move {
-                let connection = IrohConnection::accept(connection, peer_id).await?;
-                accepted_peer_sender.send((peer_id, connection)).await?;
-                Ok::<(), anyhow::Error>(())
+                let connection = IrohConnection::accept(
+                    endpoint,
+                    connection,
+                    peer_id,
+                    resumable_peer_id,
+                    &config,
+                    authenticator,
+                )
+                .await;
+                let _ = accepted_peer_sender.send((peer_id, connection)).await;
             });
         }
 
+        // Ask the application to authenticate new clients
+        self.authenticating_peers
+            .retain(|_, response| !response.is_closed());
+        while let Ok(request) = self.auth_request_receiver.try_recv() {
+            let node_id = GString::from(ticket::encode_node_id(&request.node_id));
+            let payload = PackedByteArray::from(request.payload);
+            self.authenticating_peers
+                .insert(request.node_id, request.response);
+            self.base_mut().emit_signal(
+                "peer_authenticating",
+                &[node_id.to_variant(), payload.to_variant()],
+            );
+        }
+
         // Register new peers
-        while let Ok((peer_id, connection)) = self.accepted_peer_receiver.try_recv() {
-            self.peers.insert(peer_id, connection);
+        while let Ok((pending_peer_id, connection)) = self.accepted_peer_receiver.try_recv() {
+            self.pending_peers.remove(&pending_peer_id);
+            let Ok((peer_id, mut connection)) = connection else {
+                continue;
+            };
+            for (channel, priority) in &self.channel_priorities {
+                connection.set_channel_priority(*channel, *priority);
+            }
+            for (channel, compression) in &self.channel_compression {
+                connection.set_channel_compression(*channel, *compression);
+            }
+            self.disconnect_reasons.remove(peer_id);
+            let node_id = connection.node_id();
+            let stale_connection = self.peers.insert(peer_id, connection);
+            if let Some(stale_connection) = &stale_connection {
+                stale_connection.close_with_reason(&DisconnectReason::new(
+                    DisconnectReason::CLOSED,
+                    "replaced by a new connection from the same node",
+                ));
+            }
+            if self.reconnecting_peers.remove(&peer_id).is_some() || stale_connection.is_some() {
+                self.base_mut()
+                    .emit_signal("peer_reconnected", &[peer_id.to_variant()]);
+                continue;
+            }
+            // The node joined again without resuming its previous peer id
+            let abandoned_peer_ids: Vec<i32> = self
+                .reconnecting_peers
+                .iter()
+                .filter(|(_, (reconnecting_node_id, _))| *reconnecting_node_id == node_id)
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            for abandoned_peer_id in abandoned_peer_ids {
+                self.reconnecting_peers.remove(&abandoned_peer_id);
+                self.base_mut()
+                    .emit_signal("peer_disconnected", &[abandoned_peer_id.to_variant()]);
+            }
             self.base_mut()
                 .emit_signal("peer_connected", &[peer_id.to_variant()]);
         }
 
         // Receive packets from peers
         let mut disconnected_peers = Vec::new();
+        let mut connection_type_changes = Vec::new();
         for (peer_id, connection) in &mut self.peers {
+            if connection.update_connection_type() {
+                let connection_type = connection_type_code(connection.connection_type());
+                connection_type_changes.push((*peer_id, connection_type));
+            }
             loop {
                 match connection.receive_packet() {
-                    Ok((channel, mode, packet)) => self
-                        .received_packets
-                        .push_back((*peer_id, channel, mode, packet)),
+                    Ok((channel, mode, packet)) => {
+                        monitors::record_queued(1);
+                        self.received_packets
+                            .push_back((*peer_id, channel, mode, packet));
+                    }
                     Err(TryRecvError::Disconnected) => {
                         disconnected_peers.push(*peer_id);
                         break;
@@ -147,33 +865,90 @@ impl IMultiplayerPeerExtension for IrohServer {
             }
         }
 
-        // Remove disconnected peers
+        // Notify connection type changes
+        for (peer_id, connection_type) in connection_type_changes {
+            self.base_mut().emit_signal(
+                "connection_type_changed",
+                &[peer_id.to_variant(), connection_type.to_variant()],
+            );
+        }
+
+        // Remove disconnected peers, keeping the peer id of lost connections for a while
         for peer_id in disconnected_peers {
-            self.peers.remove(&peer_id);
+            if let Some(connection) = self.peers.remove(&peer_id) {
+                let reason = connection.disconnect_reason();
+                if let Some(grace_period) = self.reconnect_grace_period
+                    && reason.is_connection_lost()
+                {
+                    let deadline = Instant::now() + grace_period;
+                    self.reconnecting_peers
+                        .insert(peer_id, (connection.node_id(), deadline));
+                    self.disconnect_reasons.insert(peer_id, reason);
+                    self.base_mut()
+                        .emit_signal("peer_reconnecting", &[peer_id.to_variant()]);
+                    continue;
+                }
+                self.disconnect_reasons.insert_if_absent(peer_id, reason);
+            }
             self.base_mut()
                 .emit_signal("peer_disconnected", &[peer_id.to_variant()]);
         }
+
+        // Remove peers that did not reconnect in time
+        let now = Instant::now();
+        let expired_peers: Vec<i32> = self
+            .reconnecting_peers
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in expired_peers {
+            self.reconnecting_peers.remove(&peer_id);
+            self.base_mut()
+                .emit_signal("peer_disconnected", &[peer_id.to_variant()]);
+        }
+
+        // Send the statistics of the peers to the editor
+        let instance_id = self.base().instance_id();
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, connection)| (*peer_id, connection));
+        self.debugger_reporter
+            .report(|| format!("IrohServer {instance_id}"), peers);
     }
 
     fn get_connection_status(&self) -> ConnectionStatus {
-        match self.listener.is_closed() {
-            true => ConnectionStatus::DISCONNECTED,
-            false => ConnectionStatus::CONNECTED,
+        match &self.status {
+            ServerStatus::Starting(_) => ConnectionStatus::CONNECTING,
+            ServerStatus::Listening(listener) if !listener.is_closed() => {
+                ConnectionStatus::CONNECTED
+            }
+            _ => ConnectionStatus::DISCONNECTED,
         }
     }
 
     fn close(&mut self) {
-        self.listener.close();
+        match replace(&mut self.status, ServerStatus::Closed) {
+            ServerStatus::Starting(handle) => {
+                handle.abort();
+            }
+            ServerStatus::Listening(mut listener) => {
+                let reason = DisconnectReason::from_code(DisconnectReason::SERVER_SHUTDOWN);
+                for (peer_id, connection) in &self.peers {
+                    connection.close_with_reason(&reason);
+                    self.disconnect_reasons.insert(*peer_id, reason.clone());
+                }
+                listener.close();
+                self.status = ServerStatus::Listening(listener);
+            }
+            status => self.status = status,
+        }
     }
 
     fn disconnect_peer(&mut self, peer_id: i32, force: bool) {
-        if let Some(connection) = self.peers.remove(&peer_id) {
-            connection.close();
-            if !force {
-                self.base_mut()
-                    .emit_signal("peer_disconnected", &[peer_id.to_variant()]);
-            }
-        }
+        let reason = DisconnectReason::from_code(DisconnectReason::KICKED);
+        self.disconnect_peer_with(peer_id, reason, force);
     }
 
     fn get_unique_id(&self) -> i32 {
@@ -182,9 +957,16 @@ impl IMultiplayerPeerExtension for IrohServer {
 
     fn get_max_packet_size(&self) -> i32 {
         if self.transfer_mode == TransferMode::RELIABLE {
-            u16::MAX as i32
+            self.max_packet_size
         } else {
-            1024
+            // The path to each peer can have a different datagram size
+            let max_packet_size = self
+                .peers
+                .values()
+                .map(IrohConnection::max_unreliable_packet_size)
+                .min()
+                .unwrap_or_else(|| connection::max_unreliable_packet_size(None));
+            max_packet_size as i32
         }
     }
 
@@ -215,7 +997,10 @@ impl IMultiplayerPeerExtension for IrohServer {
 
     fn get_packet_script(&mut self) -> PackedByteArray {
         match self.received_packets.pop_front() {
-            Some((_, _, _, packet)) => packet.to_vec().into(),
+            Some((_, _, _, packet)) => {
+                monitors::record_queued(-1);
+                PackedByteArray::from(&packet[..])
+            }
             _ => PackedByteArray::new(),
         }
     }
@@ -241,39 +1026,23 @@ impl IMultiplayerPeerExtension for IrohServer {
     }
 
     fn put_packet_script(&mut self, buffer: PackedByteArray) -> Error {
-        match self.target_peer_id {
-            0 => {
-                for connection in self.peers.values_mut() {
-                    connection.send_packet(
-                        self.transfer_channel,
-                        self.transfer_mode,
-                        buffer.to_vec(),
-                    );
-                }
-            }
+        let peer_ids: Vec<i32> = match self.target_peer_id {
+            0 => self.peers.keys().copied().collect(),
+            // A negative target excludes the peer with the opposite id
             peer_id if peer_id < 0 => {
-                for (other_id, connection) in &mut self.peers {
-                    if *other_id == peer_id {
-                        continue;
-                    }
-                    connection.send_packet(
-                        self.transfer_channel,
-                        self.transfer_mode,
-                        buffer.to_vec(),
-                    );
-                }
-            }
-            peer_id => {
-                if let Some(connection) = self.peers.get_mut(&peer_id) {
-                    connection.send_packet(
-                        self.transfer_channel,
-                        self.transfer_mode,
-                        buffer.to_vec(),
-                    );
-                }
+                let Some(excluded_peer_id) = peer_id.checked_neg() else {
+                    godot_error!("invalid target peer {peer_id}");
+                    return Error::ERR_INVALID_PARAMETER;
+                };
+                self.peers
+                    .keys()
+                    .copied()
+                    .filter(|other_id| *other_id != excluded_peer_id)
+                    .collect()
             }
-        }
-        Error::OK
+            peer_id => vec![peer_id],
+        };
+        self.send_to_peers(&peer_ids, buffer.as_slice())
     }
 
     fn is_server(&self) -> bool {
@@ -295,6 +1064,7 @@ impl IMultiplayerPeerExtension for IrohServer {
 
 impl Drop for IrohServer {
     fn drop(&mut self) {
+        monitors::record_queued(-(self.received_packets.len() as i64));
         self.close();
     }
 }
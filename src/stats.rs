@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use godot::prelude::*;
+use iroh::endpoint::ConnectionStats;
+
+/// The number of packets and bytes sent and received on a channel.
+#[derive(Default, Clone, Copy)]
+pub struct ChannelStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// The bytes actually sent and received once compressed.
+    pub compressed_bytes_sent: u64,
+    pub compressed_bytes_received: u64,
+}
+
+impl ChannelStats {
+    fn to_dictionary(self) -> Dictionary {
+        vdict! {
+            "packets_sent": self.packets_sent as i64,
+            "bytes_sent": self.bytes_sent as i64,
+            "packets_received": self.packets_received as i64,
+            "bytes_received": self.bytes_received as i64,
+            "compressed_bytes_sent": self.compressed_bytes_sent as i64,
+            "compressed_bytes_received": self.compressed_bytes_received as i64,
+        }
+    }
+}
+
+/// Returns the statistics of a connection as a dictionary, as documented in the README.
+pub fn stats_dictionary(
+    stats: &ConnectionStats,
+    channels: &HashMap<i32, ChannelStats>,
+) -> Dictionary {
+    let path = &stats.path;
+    let packet_loss = match path.sent_packets {
+        0 => 0.0,
+        sent_packets => path.lost_packets as f64 / sent_packets as f64,
+    };
+    let bytes_sent: u64 = channels.values().map(|stats| stats.bytes_sent).sum();
+    let compressed_bytes_sent: u64 = channels
+        .values()
+        .map(|stats| stats.compressed_bytes_sent)
+        .sum();
+    let compression_ratio = match bytes_sent {
+        0 => 1.0,
+        bytes_sent => compressed_bytes_sent as f64 / bytes_sent as f64,
+    };
+    let channels: Dictionary = channels
+        .iter()
+        .map(|(channel, stats)| (*channel, stats.to_dictionary()))
+        .collect();
+    vdict! {
+        "rtt": path.rtt.as_secs_f64() * 1000.0,
+        "packet_loss": packet_loss,
+        "lost_packets": path.lost_packets as i64,
+        "sent_packets": path.sent_packets as i64,
+        "congestion_window": path.cwnd as i64,
+        "congestion_events": path.congestion_events as i64,
+        "mtu": path.current_mtu as i64,
+        "bytes_sent": stats.udp_tx.bytes as i64,
+        "bytes_received": stats.udp_rx.bytes as i64,
+        "datagrams_sent": stats.udp_tx.datagrams as i64,
+        "datagrams_received": stats.udp_rx.datagrams as i64,
+        "compression_ratio": compression_ratio,
+        "channels": channels,
+    }
+}
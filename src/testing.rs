@@ -0,0 +1,30 @@
+//! Fixtures shared by the tests of the modules using connections.
+
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, RelayMode, Watcher};
+
+const ALPN: &[u8] = b"godot-iroh/test";
+
+/// Connects two endpoints of the same process, returning the endpoints, which must be
+/// kept alive during the test, then the connection of the client and of the server.
+pub async fn connect() -> (Endpoint, Endpoint, Connection, Connection) {
+    let server = Endpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await
+        .unwrap();
+    let client = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await
+        .unwrap();
+    let server_addr = server.node_addr().initialized().await;
+    let accept = tokio::spawn({
+        let server = server.clone();
+        async move { server.accept().await.unwrap().await.unwrap() }
+    });
+    let client_connection = client.connect(server_addr, ALPN).await.unwrap();
+    let server_connection = accept.await.unwrap();
+    (server, client, client_connection, server_connection)
+}
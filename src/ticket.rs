@@ -0,0 +1,190 @@
+//! Encoding of the connection strings used to reach a node.
+//!
+//! A ticket is the base64 encoding of the following fields:
+//!
+//! - the ticket version (1 byte)
+//! - the node id (32 bytes)
+//! - the length of the relay url (2 bytes, 0 if there is no relay url)
+//! - the relay url
+//! - the number of direct addresses (1 byte)
+//! - each direct address: its type (1 byte, 4 or 6), its ip (4 or 16 bytes) and its port (2 bytes)
+//!
+//! Legacy connection strings only contain the 32 bytes of the node id.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, bail};
+use base64::prelude::*;
+use bytes::{Buf, BufMut};
+use iroh::{NodeAddr, NodeId, RelayUrl};
+
+const TICKET_VERSION: u8 = 1;
+
+/// Encodes the address of a node into a connection string.
+pub fn encode(node_addr: &NodeAddr) -> String {
+    let mut buffer = Vec::new();
+    buffer.put_u8(TICKET_VERSION);
+    buffer.put_slice(node_addr.node_id.as_bytes());
+    match &node_addr.relay_url {
+        Some(relay_url) => {
+            let relay_url = relay_url.to_string();
+            buffer.put_u16(relay_url.len() as u16);
+            buffer.put_slice(relay_url.as_bytes());
+        }
+        None => buffer.put_u16(0),
+    }
+    let addresses = node_addr
+        .direct_addresses
+        .iter()
+        .take(u8::MAX as usize)
+        .collect::<Vec<_>>();
+    buffer.put_u8(addresses.len() as u8);
+    for address in addresses {
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                buffer.put_u8(4);
+                buffer.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buffer.put_u8(6);
+                buffer.put_slice(&ip.octets());
+            }
+        }
+        buffer.put_u16(address.port());
+    }
+    BASE64_URL_SAFE_NO_PAD.encode(buffer)
+}
+
+/// Encodes a node id alone into a legacy connection string.
+pub fn encode_node_id(node_id: &NodeId) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(node_id.as_bytes())
+}
+
+/// Decodes a connection string into the address of a node.
+///
+/// Both tickets and legacy connection strings are accepted.
+pub fn decode(connection_string: &str) -> anyhow::Result<NodeAddr> {
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(connection_string.trim())
+        .context("invalid connection string")?;
+    if bytes.len() == 32 {
+        let node_id = decode_node_id(&mut bytes.as_slice()).context("invalid connection string")?;
+        return Ok(NodeAddr::new(node_id));
+    }
+    decode_ticket(&bytes).context("invalid connection string")
+}
+
+fn decode_ticket(mut buffer: &[u8]) -> anyhow::Result<NodeAddr> {
+    let version = buffer.try_get_u8()?;
+    if version != TICKET_VERSION {
+        bail!("unsupported ticket version {version}");
+    }
+    let mut node_addr = NodeAddr::new(decode_node_id(&mut buffer)?);
+    let relay_url_len = buffer.try_get_u16()? as usize;
+    if relay_url_len > 0 {
+        if buffer.remaining() < relay_url_len {
+            bail!("truncated relay url");
+        }
+        let relay_url = std::str::from_utf8(&buffer[..relay_url_len])?;
+        node_addr.relay_url = Some(relay_url.parse::<RelayUrl>()?);
+        buffer.advance(relay_url_len);
+    }
+    for _ in 0..buffer.try_get_u8()? {
+        let ip = match buffer.try_get_u8()? {
+            4 => {
+                let mut octets = [0u8; 4];
+                buffer.try_copy_to_slice(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let mut octets = [0u8; 16];
+                buffer.try_copy_to_slice(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            tag => bail!("unknown address type {tag}"),
+        };
+        let port = buffer.try_get_u16()?;
+        node_addr.direct_addresses.insert(SocketAddr::new(ip, port));
+    }
+    if buffer.has_remaining() {
+        bail!("unexpected trailing bytes");
+    }
+    Ok(node_addr)
+}
+
+fn decode_node_id(buffer: &mut &[u8]) -> anyhow::Result<NodeId> {
+    let mut node_id_bytes = [0u8; 32];
+    buffer.try_copy_to_slice(&mut node_id_bytes)?;
+    Ok(NodeId::from_bytes(&node_id_bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn node_addr() -> NodeAddr {
+        let node_id = SecretKey::from_bytes(&[7u8; 32]).public();
+        NodeAddr::new(node_id)
+            .with_relay_url("https://relay.example.com./".parse().unwrap())
+            .with_direct_addresses([
+                "192.168.1.2:1234".parse().unwrap(),
+                "[2001:db8::1]:5678".parse().unwrap(),
+            ])
+    }
+
+    #[test]
+    fn round_trips_tickets() {
+        let node_addr = node_addr();
+        assert_eq!(decode(&encode(&node_addr)).unwrap(), node_addr);
+
+        let node_addr = NodeAddr::new(node_addr.node_id);
+        assert_eq!(decode(&encode(&node_addr)).unwrap(), node_addr);
+    }
+
+    #[test]
+    fn decodes_legacy_connection_strings() {
+        let node_id = node_addr().node_id;
+        let connection_string = encode_node_id(&node_id);
+        assert_eq!(decode(&connection_string).unwrap(), NodeAddr::new(node_id));
+        assert_eq!(
+            decode(&format!("  {connection_string}\n")).unwrap(),
+            NodeAddr::new(node_id)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_tickets() {
+        let bytes = BASE64_URL_SAFE_NO_PAD.decode(encode(&node_addr())).unwrap();
+        for len in 0..bytes.len() {
+            // Legacy connection strings are exactly a node id
+            if len == 32 {
+                continue;
+            }
+            let connection_string = BASE64_URL_SAFE_NO_PAD.encode(&bytes[..len]);
+            assert!(decode(&connection_string).is_err(), "length {len}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_tickets() {
+        assert!(decode("").is_err());
+        assert!(decode("not a ticket!").is_err());
+
+        let mut bytes = BASE64_URL_SAFE_NO_PAD.decode(encode(&node_addr())).unwrap();
+        bytes.push(0);
+        assert!(decode(&BASE64_URL_SAFE_NO_PAD.encode(&bytes)).is_err());
+
+        bytes.pop();
+        bytes[0] = TICKET_VERSION + 1;
+        assert!(decode(&BASE64_URL_SAFE_NO_PAD.encode(&bytes)).is_err());
+
+        let mut bytes = BASE64_URL_SAFE_NO_PAD
+            .decode(encode(&NodeAddr::new(node_addr().node_id)))
+            .unwrap();
+        *bytes.last_mut().unwrap() = 1;
+        bytes.extend_from_slice(&[5, 0, 0, 0, 0, 0, 0]);
+        assert!(decode(&BASE64_URL_SAFE_NO_PAD.encode(&bytes)).is_err());
+    }
+}